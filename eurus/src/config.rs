@@ -1,10 +1,16 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Config {
     pub mqtt: Mqtt,
     pub db: Db,
     pub runtime: Runtime,
+    #[serde(default)]
+    pub timers: Timers,
+    #[serde(default)]
+    pub packs: Packs,
+    #[serde(default)]
+    pub content: Content,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -22,9 +28,147 @@ pub struct Db {
     pub database: String,
     pub users_collection: String,
     pub rooms_collection: String,
+    #[serde(default = "default_events_collection")]
+    pub events_collection: String, // append-only log of what happened in rooms
+}
+
+fn default_events_collection() -> String {
+    "room_events".into()
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Runtime {
     pub server_address: String,
+    // how long rooms have to close after the server got asked to stop
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    // every room gets this seed instead of a random one, the same seed
+    // and the same requests play out the same game so it helps reproduce bugs
+    #[serde(default)]
+    pub seed: Option<u64>,
+    // listing and removing rooms over http needs this token
+    // in the authorization header, without it those routes are off
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    // how many spectators can watch a single room
+    #[serde(default = "default_spectators_limit")]
+    pub spectators_limit: usize,
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    5
+}
+
+fn default_spectators_limit() -> usize {
+    50
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Packs {
+    pub dir: String, // where question packs are loaded from
+}
+
+impl Default for Packs {
+    fn default() -> Self {
+        Self {
+            dir: "res/packs".into(),
+        }
+    }
+}
+
+/// What players are allowed to write, lengths are counted in characters.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Content {
+    pub max_question_len: usize,
+    pub max_answer_len: usize,
+    // questions and answers with any of these words are rejected
+    pub banned_words: Vec<String>,
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Self {
+            max_question_len: 200,
+            max_answer_len: 200,
+            banned_words: Vec::new(),
+        }
+    }
+}
+
+/// Time limits for each phase of the game, 0 turns the limit off.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Timers {
+    pub questions_secs: u64,
+    pub answers_secs: u64,
+    pub polling_secs: u64,
+    // how often players are told how much time they have left
+    pub tick_secs: u64,
+    pub idle_policy: IdlePolicy,
+    // how long disconnected players have to rejoin before they are removed
+    pub rejoin_grace_secs: u64,
+    // how long the host has to start a rematch once the game is over,
+    // 0 turns rematches off and closes the room right away
+    pub rematch_secs: u64,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self {
+            questions_secs: 120,
+            answers_secs: 90,
+            polling_secs: 60,
+            tick_secs: 10,
+            idle_policy: IdlePolicy::Ignore,
+            rejoin_grace_secs: 30,
+            rematch_secs: 60,
+        }
+    }
+}
+
+/// What happens to players who did not answer or vote in time.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum IdlePolicy {
+    Ignore, // the round goes on without them
+    Kick,   // they are removed from the room
+}
+
+#[cfg(test)]
+pub(crate) fn test_config() -> Config {
+    Config {
+        mqtt: Mqtt {
+            host: "tcp://localhost:1883".into(),
+            user: "".into(),
+            password: "".into(),
+        },
+        db: Db {
+            host: "mongodb://localhost:27017".into(),
+            user: "".into(),
+            password: "".into(),
+            database: "eurusDB".into(),
+            users_collection: "mqtt_users".into(),
+            rooms_collection: "rooms".into(),
+            events_collection: "room_events".into(),
+        },
+        runtime: Runtime {
+            server_address: "127.0.0.1:3005".into(),
+            shutdown_grace_secs: 5,
+            seed: None,
+            admin_token: None,
+            spectators_limit: 2,
+        },
+        timers: Timers {
+            questions_secs: 0,
+            answers_secs: 0,
+            polling_secs: 0,
+            tick_secs: 0,
+            idle_policy: IdlePolicy::Ignore,
+            rejoin_grace_secs: 0,
+            rematch_secs: 0,
+        },
+        packs: Packs::default(),
+        content: Content::default(),
+    }
 }
@@ -8,6 +8,7 @@ pub struct Connection {
     pub db: mongodb::Database,
     pub users_col: mongodb::Collection,
     pub rooms_col: mongodb::Collection,
+    pub events_col: mongodb::Collection,
 }
 
 impl Connection {
@@ -35,11 +36,13 @@ impl Connection {
         }
         let users_col = db.collection(&config.db.users_collection);
         let rooms_col = db.collection(&config.db.rooms_collection);
+        let events_col = db.collection(&config.db.events_collection);
         Ok(Self {
             cli,
             db,
             users_col,
             rooms_col,
+            events_col,
         })
     }
 }
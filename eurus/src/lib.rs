@@ -1,5 +1,6 @@
 pub mod config;
 pub mod db;
+pub mod packs;
 pub mod repository;
 pub mod room;
 pub mod service;
@@ -1,10 +1,11 @@
-use std::{convert::Infallible, net::SocketAddr, path::Path, str::FromStr};
+use std::{convert::Infallible, net::SocketAddr, path::Path, str::FromStr, time::Duration};
 use tracing::Instrument;
 
 use futures::TryStreamExt;
+use serde::{de::DeserializeOwned, Serialize};
 
 use hyper::{
-    http::{response, StatusCode},
+    http::{header, response, StatusCode},
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server,
 };
@@ -12,8 +13,12 @@ use tracing::{error, info};
 
 use eurus::{
     config::Config,
+    packs::PackLibrary,
     repository::{DataRepository, RepReq, RepReqChannel, RepResp},
-    service::{create_new_room, dto},
+    service::{
+        create_new_room, dto, join_room, registry::RoomRegistry, resume_rooms, spectate_room,
+        JoinRoomError, RoomCreationError,
+    },
 };
 
 #[tokio::main]
@@ -35,6 +40,13 @@ async fn main() {
         eprintln!("logger couldn't be setup {}", e);
         return;
     }
+    let packs = match PackLibrary::load(&config.packs.dir) {
+        Ok(packs) => packs,
+        Err(e) => {
+            eprintln!("question packs couldn't be loaded {:?}", e);
+            return;
+        }
+    };
     let (task, mut room_rep_chan) = match DataRepository::new_task(&config).await {
         Ok(val) => val,
         Err(err) => {
@@ -43,9 +55,17 @@ async fn main() {
         }
     };
     let room_rep_task = tokio::spawn(task);
-    if let Err(e) = run_server(&config, room_rep_chan.clone()).await {
+    let registry = RoomRegistry::new();
+    match resume_rooms(room_rep_chan.clone(), config.clone(), registry.clone()).await {
+        Ok(resumed) => info!("Resumed {} rooms", resumed),
+        Err(e) => eprintln!("could not resume rooms: {}", e),
+    }
+    if let Err(e) = run_server(&config, room_rep_chan.clone(), registry.clone(), packs).await {
         eprintln!("server error: {}", e);
     }
+    // rooms still need the repository to clean up after themselves
+    let grace = Duration::from_secs(config.runtime.shutdown_grace_secs);
+    registry.shutdown_all(grace).await;
     match DataRepository::send_req(&mut room_rep_chan, RepReq::Close).await {
         Ok(RepResp::ClosingRepository) => (),
         Ok(_) => {
@@ -83,20 +103,29 @@ fn read_config<P: AsRef<Path>>(path: P) -> anyhow::Result<Config> {
     Ok(toml::from_str(contents)?)
 }
 
-#[tracing::instrument(skip(rep))]
-async fn run_server(config: &Config, rep: RepReqChannel) -> anyhow::Result<()> {
+#[tracing::instrument(skip(rep, registry, packs))]
+async fn run_server(
+    config: &Config,
+    rep: RepReqChannel,
+    registry: RoomRegistry,
+    packs: PackLibrary,
+) -> anyhow::Result<()> {
     let addr = SocketAddr::from_str(&config.runtime.server_address)?;
     let make_svc = make_service_fn(move |_| {
         // Why do we need 2 levels of clone?
         let conf = config.clone(); // <---- one here
         let span = tracing::debug_span!("service creation");
         let rep_clone = rep.clone(); // <----- here
+        let registry_clone = registry.clone();
+        let packs_clone = packs.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
                 let span = tracing::debug_span!("request span");
                 let rep = rep_clone.clone(); // <--- and the second one here
                 let conf = conf.clone(); // <---- here
-                async move { Ok::<_, Infallible>(handle_req(req, rep, conf).await) }
+                let registry = registry_clone.clone();
+                let packs = packs_clone.clone();
+                async move { Ok::<_, Infallible>(handle_req(req, rep, conf, registry, packs).await) }
                     .instrument(span)
             }))
         }
@@ -107,17 +136,138 @@ async fn run_server(config: &Config, rep: RepReqChannel) -> anyhow::Result<()> {
     Ok(server.await?)
 }
 
-#[tracing::instrument(skip(rep))]
-async fn handle_req(req: Request<Body>, rep: RepReqChannel, config: Config) -> Response<Body> {
+static ROOMS_PATH: &str = "/rooms/";
+
+#[tracing::instrument(skip(rep, registry, packs))]
+async fn handle_req(
+    req: Request<Body>,
+    rep: RepReqChannel,
+    config: Config,
+    registry: RoomRegistry,
+    packs: PackLibrary,
+) -> Response<Body> {
     match (req.method(), req.uri().path()) {
-        (&Method::POST, "/new_room") => new_room(req, rep, config).await,
+        (&Method::POST, "/new_room") => new_room(req, rep, config, registry, packs).await,
+        (&Method::POST, "/join_room") => new_player(req, rep, config).await,
+        (&Method::POST, "/spectate_room") => new_spectator(req, rep, config).await,
+        (&Method::GET, "/rooms") if !is_admin(&req, &config) => admin_only(),
+        (&Method::GET, "/rooms") => json_response(&registry.list().await),
+        (&Method::GET, "/packs") => json_response(&packs.list()),
+        (&Method::GET, path) if path.starts_with(ROOMS_PATH) => {
+            match registry.status(&path[ROOMS_PATH.len()..]).await {
+                Some(status) => json_response(&status),
+                None => error_response("room not found", StatusCode::NOT_FOUND),
+            }
+        }
+        (&Method::DELETE, path) if path.starts_with(ROOMS_PATH) && !is_admin(&req, &config) => {
+            admin_only()
+        }
+        (&Method::DELETE, path) if path.starts_with(ROOMS_PATH) => {
+            if registry.shutdown(&path[ROOMS_PATH.len()..]).await {
+                response::Builder::new()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap()
+            } else {
+                error_response("room not found", StatusCode::NOT_FOUND)
+            }
+        }
         _ => error_response("not found", StatusCode::NOT_FOUND),
     }
 }
 
-#[tracing::instrument(skip(rep))]
-async fn new_room(req: Request<Body>, rep: RepReqChannel, config: Config) -> Response<Body> {
+/// Whether the request carries the admin token from the config,
+/// there are no admins if the config does not set one.
+fn is_admin(req: &Request<Body>, config: &Config) -> bool {
+    let token = match &config.runtime.admin_token {
+        Some(token) => token,
+        None => return false,
+    };
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        == Some(token.as_str())
+}
+
+fn admin_only() -> Response<Body> {
+    error_response("admin token required", StatusCode::FORBIDDEN)
+}
+
+#[tracing::instrument(skip(rep, registry, packs))]
+async fn new_room(
+    req: Request<Body>,
+    rep: RepReqChannel,
+    config: Config,
+    registry: RoomRegistry,
+    packs: PackLibrary,
+) -> Response<Body> {
     // todo: check if both are within limits
+    let body: dto::NewRoomReq = match read_json(req).await {
+        Ok(val) => val,
+        Err(resp) => return resp,
+    };
+    match create_new_room(rep, config, registry, packs, body).await {
+        Ok(rd) => json_response(&rd),
+        Err(
+            e @ RoomCreationError::UnknownPack(_)
+            | e @ RoomCreationError::InvalidTeams
+            | e @ RoomCreationError::NoSuddenDeathQuestions,
+        ) => error_response(e.to_string(), StatusCode::BAD_REQUEST),
+        Err(e) => {
+            error!("There was en error while creating a new room: {}", e);
+            error_response("internal server error", StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[tracing::instrument(skip(rep))]
+async fn new_player(req: Request<Body>, rep: RepReqChannel, config: Config) -> Response<Body> {
+    let body: dto::NewPlayerReq = match read_json(req).await {
+        Ok(val) => val,
+        Err(resp) => return resp,
+    };
+    match join_room(rep, config, body).await {
+        Ok(pd) => json_response(&pd),
+        Err(e @ JoinRoomError::InvalidRoomId) => {
+            error_response(e.to_string(), StatusCode::BAD_REQUEST)
+        }
+        Err(e @ JoinRoomError::RoomNotFound) => {
+            error_response(e.to_string(), StatusCode::NOT_FOUND)
+        }
+        Err(e @ JoinRoomError::RoomFull) => error_response(e.to_string(), StatusCode::CONFLICT),
+        Err(e @ JoinRoomError::Banned) => error_response(e.to_string(), StatusCode::FORBIDDEN),
+        Err(e) => {
+            error!("There was en error while joining a room: {}", e);
+            error_response("internal server error", StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[tracing::instrument(skip(rep))]
+async fn new_spectator(req: Request<Body>, rep: RepReqChannel, config: Config) -> Response<Body> {
+    let body: dto::NewSpectatorReq = match read_json(req).await {
+        Ok(val) => val,
+        Err(resp) => return resp,
+    };
+    match spectate_room(rep, config, body).await {
+        Ok(sd) => json_response(&sd),
+        Err(e @ JoinRoomError::InvalidRoomId) => {
+            error_response(e.to_string(), StatusCode::BAD_REQUEST)
+        }
+        Err(e @ JoinRoomError::RoomNotFound) => {
+            error_response(e.to_string(), StatusCode::NOT_FOUND)
+        }
+        Err(e @ JoinRoomError::RoomFull) => error_response(e.to_string(), StatusCode::CONFLICT),
+        Err(e @ JoinRoomError::Banned) => error_response(e.to_string(), StatusCode::FORBIDDEN),
+        Err(e) => {
+            error!("There was en error while spectating a room: {}", e);
+            error_response("internal server error", StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
     let (_, body) = req.into_parts();
     let body = match body
         .try_fold(Vec::new(), |mut acc, chunk| async move {
@@ -127,29 +277,21 @@ async fn new_room(req: Request<Body>, rep: RepReqChannel, config: Config) -> Res
         .await
     {
         Ok(val) => val,
-        Err(_) => return error_response("could not assemble message", StatusCode::BAD_REQUEST),
-    };
-    let body: dto::NewRoomReq = match serde_json::from_slice(&body) {
-        Ok(val) => val,
-        Err(_) => return error_response("could not decode message", StatusCode::BAD_REQUEST),
-    };
-    match create_new_room(rep, config, body).await {
-        Ok(rd) => {
-            let body = match serde_json::to_vec(&rd) {
-                Ok(val) => val,
-                Err(_) => {
-                    return error_response(
-                        "internal server error",
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                    )
-                }
-            };
-            Response::new(Body::from(body))
-        }
-        Err(e) => {
-            error!("There was en error while creating a new room: {}", e);
-            error_response("internal server error", StatusCode::INTERNAL_SERVER_ERROR)
+        Err(_) => {
+            return Err(error_response(
+                "could not assemble message",
+                StatusCode::BAD_REQUEST,
+            ))
         }
+    };
+    serde_json::from_slice(&body)
+        .map_err(|_| error_response("could not decode message", StatusCode::BAD_REQUEST))
+}
+
+fn json_response<T: Serialize>(val: &T) -> Response<Body> {
+    match serde_json::to_vec(val) {
+        Ok(body) => Response::new(Body::from(body)),
+        Err(_) => error_response("internal server error", StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
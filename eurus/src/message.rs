@@ -1,36 +1,555 @@
+//! Messages exchanged between the room runtime and its clients over mqtt.
+//!
+//! Every message is a JSON object with a `type` field naming the variant
+//! and, if the variant carries a payload, a `data` field holding it.
+//! Unit variants have no `data` field at all. For example:
+//!
+//! ```json
+//! {"type": "GetRoomState"}
+//! {"type": "JoinRoom", "data": {"name": "Alice", "token": 1234}}
+//! {"type": "SelectAnswer", "data": {"answer": 2}}
+//! {"type": "Err", "data": "AnswerAlreadySent"}
+//! ```
+//!
+//! Field and variant names are part of the protocol so renaming any of them
+//! is a breaking change for the clients.
+use std::collections::BTreeSet;
+
 use serde::{Deserialize, Serialize};
 
-//
-// WIP: Messages
-//
-#[derive(Debug, Serialize, Deserialize)]
+use crate::room::model::{
+    AnswerId, PlayerId, PlayerToken, QuestionId, Room, RoomState, Round, RoundState, TeamId,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum Request {
     GetRoomState, // so the client can get the latest state if they wish to
-    JoinRoom,     // send player data associated with this
-    AddQuestion,
-    AddAnswer,
-    SelectAnswer,
+    JoinRoom {
+        name: String,
+        token: PlayerToken,
+    },
+    AddQuestion {
+        content: String,
+    },
+    AddAnswer {
+        content: String,
+    },
+    SelectAnswer {
+        answer: AnswerId,
+    },
+    Rejoin {
+        token: PlayerToken,
+    }, // after losing the connection
+    Host {
+        token: PlayerToken,
+        cmd: HostCommand,
+    },
     Disconnecting,
 }
 
-type PlayerId = usize;
+/// Requests only the room's host can make,
+/// the host is whoever holds the token given out on the room's creation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum HostCommand {
+    StartGame, // without waiting for more players or questions
+    Kick {
+        player: PlayerId,
+    },
+    Ban {
+        player: PlayerId,
+    }, // kicks the player for good
+    RejectQuestion {
+        question: QuestionId,
+    },
+    SkipRound,
+    ChangeLimits {
+        players_limit: Option<usize>,
+        rounds_limit: Option<usize>,
+    },
+    EndGame,
+    Rematch {
+        players_limit: Option<usize>,
+        rounds_limit: Option<usize>,
+    }, // plays again with the same players once the game is over
+    AssignTeam {
+        player: PlayerId,
+        team: TeamId,
+    }, // players are put in the smallest team when they join
+}
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
 pub enum Response {
     RuntimeStarted,
-    NewPlayerJoined,    // send public player data to the rest
-    PlayerDisconnected, // send disconnected player identifier
-    QuestionAdded,
-    NewRound,
-    GameScore,
-    RoomState,
+    NewPlayerJoined(PlayerInfo),
+    PlayerDisconnected {
+        id: PlayerId,
+    },
+    PlayerKicked {
+        id: PlayerId,
+        banned: bool,
+    },
+    LimitsChanged {
+        players_limit: usize,
+        rounds_limit: usize,
+    },
+    TeamAssigned {
+        player: PlayerId,
+        team: TeamId,
+    },
+    QuestionAdded {
+        id: QuestionId,
+        author: PlayerId,
+    },
+    QuestionRejected {
+        id: QuestionId,
+    },
+    NewRound {
+        round_num: usize,
+        question: String,
+    },
+    RoundSkipped {
+        round_num: usize,
+    },
+    SuddenDeath {
+        players: Vec<PlayerId>,
+    }, // only the tied players answer the rounds that follow
+    AnswerAdded {
+        author: PlayerId,
+    },
+    PollingStarted {
+        answers: Vec<AnswerInfo>,
+    },
+    AnswerSelected {
+        voter: PlayerId,
+    },
+    TimeLeft {
+        phase: Phase,
+        secs: u64,
+    },
+    GameScore(ScoreTable),
+    GameFinished,
+    RematchStarted {
+        players_limit: usize,
+        rounds_limit: usize,
+    },
+    RoomClosed,
+    RoomState(RoomSnapshot),
+    ServerShuttingDown,
+    RuntimeLost, // sent by the broker if the runtime's connection drops
     Err(ErrResponse),
     Priv(PlayerId, Box<Response>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ErrResponse {
     QuestionLimitReached,
     AnswerAlreadySent,
     AnswerAlreadySelected,
+    RoomFull,
+    AlreadyJoined,
+    NotJoined,
+    NoSuchAnswer,
+    OwnAnswer,
+    OwnTeam,
+    NoSuchPlayer,
+    NoSuchQuestion,
+    NoSuchTeam,
+    InvalidToken,
+    InvalidLimits,
+    NotHost,
+    CannotKickHost, // the host cannot kick or ban themselves
+    Banned,
+    UnexpectedRequest,
+    EmptyContent,
+    ContentTooLong,
+    /// The same question or answer was already given. For answers this lets
+    /// the player know somebody else wrote it before polling starts, which is
+    /// intended: identical answers could not be told apart when voting.
+    DuplicateContent,
+    InappropriateContent, // contains one of the banned words
+}
+
+/// Parts of the game that have a time limit.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Phase {
+    Questions,
+    Answers,
+    Polling,
+    Rematch, // left to start a rematch
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInfo {
+    pub id: PlayerId,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<TeamId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnswerInfo {
+    pub id: AnswerId,
+    pub content: String,
+}
+
+impl AnswerInfo {
+    /// Answers given in the round, sorted by their ids.
+    pub fn of_round(round: &Round) -> Vec<Self> {
+        let mut answers: Vec<_> = round
+            .answers
+            .values()
+            .map(|a| AnswerInfo {
+                id: a.id,
+                content: a.content.clone(),
+            })
+            .collect();
+        answers.sort_unstable_by_key(|a| a.id);
+        answers
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerScore {
+    pub id: PlayerId,
+    pub name: String,
+    pub points: i64,
+    pub round_points: i64,          // points gained in the last round
+    pub breakdown: Vec<ScoreEntry>, // where the round points came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub team: Option<TeamId>,
+}
+
+/// Team's standing, it has all the points its players have.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TeamScore {
+    pub id: TeamId,
+    pub players: Vec<PlayerId>,
+    pub points: i64,
+    pub round_points: i64,
+}
+
+impl TeamScore {
+    /// Standings of the teams the players belong to, sorted from the best one.
+    pub fn of_players(scores: &[PlayerScore]) -> Vec<Self> {
+        let teams: BTreeSet<_> = scores.iter().filter_map(|s| s.team).collect();
+        let mut standings: Vec<_> = teams
+            .into_iter()
+            .map(|id| {
+                let members = scores.iter().filter(|s| s.team == Some(id));
+                TeamScore {
+                    id,
+                    players: members.clone().map(|s| s.id).collect(),
+                    points: members.clone().map(|s| s.points).sum(),
+                    round_points: members.map(|s| s.round_points).sum(),
+                }
+            })
+            .collect();
+        standings.sort_by(|a, b| b.points.cmp(&a.points).then(a.id.cmp(&b.id)));
+        standings
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreEntry {
+    pub reason: ScoreReason,
+    pub points: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScoreReason {
+    Votes,
+    RoundWinner,
+    Unanimous,
+    NoAnswer,
+    FinalRound,
+    Audience,
+}
+
+/// Scores after the round `round_num`, sorted from the best player
+/// and, when playing in teams, from the best team.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoreTable {
+    pub round_num: usize,
+    pub scores: Vec<PlayerScore>,
+    pub answers: Vec<AnswerResult>, // who wrote what, revealed once the round is over
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub teams: Vec<TeamScore>,
+    // who won the game, only in the table sent after the last round
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub winners: Vec<PlayerId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnswerResult {
+    pub id: AnswerId,
+    pub author: PlayerId,
+    pub content: String,
+    pub votes: usize,
+}
+
+impl AnswerResult {
+    /// Answers of the round with their authors, the most voted first.
+    pub fn of_round(round: &Round) -> Vec<Self> {
+        let mut answers: Vec<_> = round
+            .answers
+            .values()
+            .map(|a| AnswerResult {
+                id: a.id,
+                author: a.player_id,
+                content: a.content.clone(),
+                votes: round.polls.values().filter(|id| **id == a.id).count(),
+            })
+            .collect();
+        answers.sort_unstable_by(|a, b| b.votes.cmp(&a.votes).then(a.id.cmp(&b.id)));
+        answers
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundSnapshot {
+    pub round_num: usize,
+    pub state: RoundState,
+    pub question: String,
+    pub answered: Vec<PlayerId>,
+    pub voted: Vec<PlayerId>,
+    // answers to vote for, empty until polling starts
+    #[serde(default)]
+    pub answers: Vec<AnswerInfo>,
+}
+
+/// Public view of the room, it never contains the room's password,
+/// players' tokens nor who wrote the answers of the current round.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub state: RoomState,
+    pub players_limit: usize,
+    pub rounds_limit: usize,
+    pub players: Vec<PlayerScore>,
+    pub questions_count: usize,
+    pub past_rounds: usize,
+    pub round: Option<RoundSnapshot>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sudden_death: Vec<PlayerId>,
+}
+
+impl From<&Room> for RoomSnapshot {
+    fn from(room: &Room) -> Self {
+        let round = room.curr_round.as_ref().map(|round| {
+            let mut answered: Vec<_> = round.answers.keys().copied().collect();
+            let mut voted: Vec<_> = round.polls.keys().copied().collect();
+            answered.sort_unstable();
+            voted.sort_unstable();
+            let answers = match round.state {
+                RoundState::Polling => AnswerInfo::of_round(round),
+                RoundState::AcceptingAnswers => Vec::new(),
+            };
+            RoundSnapshot {
+                round_num: round.round_num,
+                state: round.state,
+                question: round.question.content.clone(),
+                answered,
+                voted,
+                answers,
+            }
+        });
+        Self {
+            state: room.state,
+            players_limit: room.players_limit,
+            rounds_limit: room.rounds_limit,
+            players: room
+                .players
+                .iter()
+                .map(|p| PlayerScore {
+                    id: p.id,
+                    name: p.name.clone(),
+                    points: p.points,
+                    round_points: 0,
+                    breakdown: Vec::new(),
+                    team: p.team,
+                })
+                .collect(),
+            questions_count: room.questions.len(),
+            past_rounds: room.past_rounds.len(),
+            round,
+            sudden_death: room.sudden_death.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn round_trip<T>(val: T, expected: serde_json::Value)
+    where
+        T: Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let encoded = serde_json::to_value(&val).unwrap();
+        assert_eq!(encoded, expected);
+        let decoded: T = serde_json::from_value(encoded).unwrap();
+        assert_eq!(decoded, val);
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip(Request::GetRoomState, json!({"type": "GetRoomState"}));
+        round_trip(
+            Request::JoinRoom {
+                name: "Alice".into(),
+                token: 1234,
+            },
+            json!({"type": "JoinRoom", "data": {"name": "Alice", "token": 1234}}),
+        );
+        round_trip(
+            Request::AddQuestion {
+                content: "why?".into(),
+            },
+            json!({"type": "AddQuestion", "data": {"content": "why?"}}),
+        );
+        round_trip(
+            Request::AddAnswer {
+                content: "because".into(),
+            },
+            json!({"type": "AddAnswer", "data": {"content": "because"}}),
+        );
+        round_trip(
+            Request::SelectAnswer { answer: 2 },
+            json!({"type": "SelectAnswer", "data": {"answer": 2}}),
+        );
+        round_trip(
+            Request::Rejoin { token: 1234 },
+            json!({"type": "Rejoin", "data": {"token": 1234}}),
+        );
+        round_trip(
+            Request::Host {
+                token: 1234,
+                cmd: HostCommand::Kick { player: 2 },
+            },
+            json!({
+                "type": "Host",
+                "data": {"token": 1234, "cmd": {"type": "Kick", "data": {"player": 2}}}
+            }),
+        );
+        round_trip(
+            Request::Host {
+                token: 1234,
+                cmd: HostCommand::SkipRound,
+            },
+            json!({"type": "Host", "data": {"token": 1234, "cmd": {"type": "SkipRound"}}}),
+        );
+        round_trip(Request::Disconnecting, json!({"type": "Disconnecting"}));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        round_trip(Response::RuntimeStarted, json!({"type": "RuntimeStarted"}));
+        round_trip(Response::RuntimeLost, json!({"type": "RuntimeLost"}));
+        round_trip(
+            Response::NewPlayerJoined(PlayerInfo {
+                id: 1,
+                name: "Bob".into(),
+                team: None,
+            }),
+            json!({"type": "NewPlayerJoined", "data": {"id": 1, "name": "Bob"}}),
+        );
+        round_trip(
+            Response::PollingStarted {
+                answers: vec![AnswerInfo {
+                    id: 0,
+                    content: "because".into(),
+                }],
+            },
+            json!({
+                "type": "PollingStarted",
+                "data": {"answers": [{"id": 0, "content": "because"}]}
+            }),
+        );
+        round_trip(
+            Response::GameScore(ScoreTable {
+                round_num: 0,
+                scores: vec![PlayerScore {
+                    id: 1,
+                    name: "Bob".into(),
+                    points: 3,
+                    round_points: 1,
+                    breakdown: vec![ScoreEntry {
+                        reason: ScoreReason::Votes,
+                        points: 1,
+                    }],
+                    team: None,
+                }],
+                answers: vec![AnswerResult {
+                    id: 7,
+                    author: 2,
+                    content: "because".into(),
+                    votes: 1,
+                }],
+                teams: Vec::new(),
+                winners: Vec::new(),
+            }),
+            json!({
+                "type": "GameScore",
+                "data": {
+                    "round_num": 0,
+                    "scores": [{
+                        "id": 1,
+                        "name": "Bob",
+                        "points": 3,
+                        "round_points": 1,
+                        "breakdown": [{"reason": "Votes", "points": 1}]
+                    }],
+                    "answers": [{"id": 7, "author": 2, "content": "because", "votes": 1}]
+                }
+            }),
+        );
+        round_trip(
+            Response::TimeLeft {
+                phase: Phase::Answers,
+                secs: 30,
+            },
+            json!({"type": "TimeLeft", "data": {"phase": "Answers", "secs": 30}}),
+        );
+        round_trip(
+            Response::Priv(3, Box::new(Response::Err(ErrResponse::RoomFull))),
+            json!({"type": "Priv", "data": [3, {"type": "Err", "data": "RoomFull"}]}),
+        );
+    }
+
+    #[test]
+    fn room_snapshot_round_trip() {
+        let mut room = Room::new([0; 12], 42, 2, 1);
+        room.players.push(crate::room::model::Player {
+            id: 0,
+            token: 7,
+            name: "Alice".into(),
+            points: 2,
+            team: None,
+        });
+        let snapshot = RoomSnapshot::from(&room);
+        round_trip(
+            Response::RoomState(snapshot),
+            json!({
+                "type": "RoomState",
+                "data": {
+                    "state": "AcceptingPlayers",
+                    "players_limit": 2,
+                    "rounds_limit": 1,
+                    "players": [{
+                        "id": 0,
+                        "name": "Alice",
+                        "points": 2,
+                        "round_points": 0,
+                        "breakdown": []
+                    }],
+                    "questions_count": 0,
+                    "past_rounds": 0,
+                    "round": null
+                }
+            }),
+        );
+    }
 }
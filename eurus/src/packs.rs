@@ -0,0 +1,186 @@
+//! Question packs rooms can take their questions from.
+//!
+//! Packs are TOML or JSON files kept in the directory set in the config,
+//! all of them are loaded once at startup. A pack looks like so:
+//!
+//! ```toml
+//! id = "general-en"
+//! language = "en"
+//! tags = ["general", "easy"]
+//! questions = ["What is the best thing about mondays?"]
+//! ```
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::service::dto;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuestionPack {
+    pub id: String,
+    pub language: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub questions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PackLibrary {
+    packs: Arc<HashMap<String, QuestionPack>>,
+}
+
+impl PackLibrary {
+    pub fn new(packs: Vec<QuestionPack>) -> anyhow::Result<Self> {
+        let mut by_id = HashMap::new();
+        for pack in packs {
+            if by_id.contains_key(&pack.id) {
+                return Err(anyhow!("question pack {} is defined twice", pack.id));
+            }
+            by_id.insert(pack.id.clone(), pack);
+        }
+        Ok(Self {
+            packs: Arc::new(by_id),
+        })
+    }
+
+    /// Loads every `.toml` and `.json` file in `dir`.
+    /// A missing directory just means there are no packs.
+    pub fn load<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.exists() {
+            warn!("question packs directory {} does not exist", dir.display());
+            return Ok(Self::default());
+        }
+        let mut packs = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let pack: anyhow::Result<QuestionPack> =
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("toml") => toml::from_str(&std::fs::read_to_string(&path)?)
+                        .map_err(anyhow::Error::from),
+                    Some("json") => serde_json::from_str(&std::fs::read_to_string(&path)?)
+                        .map_err(anyhow::Error::from),
+                    _ => continue,
+                };
+            packs.push(pack.with_context(|| format!("invalid pack {}", path.display()))?);
+        }
+        info!("loaded {} question packs", packs.len());
+        Self::new(packs)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&QuestionPack> {
+        self.packs.get(id)
+    }
+
+    pub fn list(&self) -> Vec<dto::PackInfo> {
+        let mut packs: Vec<_> = self
+            .packs
+            .values()
+            .map(|pack| dto::PackInfo {
+                id: pack.id.clone(),
+                language: pack.language.clone(),
+                tags: pack.tags.clone(),
+                questions: pack.questions.len(),
+            })
+            .collect();
+        packs.sort_by(|a, b| a.id.cmp(&b.id));
+        packs
+    }
+
+    /// Returns the first of `ids` that is not a known pack.
+    pub fn find_missing<'a>(&self, ids: &'a [String]) -> Option<&'a str> {
+        ids.iter()
+            .find(|id| !self.packs.contains_key(id.as_str()))
+            .map(String::as_str)
+    }
+
+    /// Picks at most `count` random questions out of the packs,
+    /// unknown packs are skipped.
+    pub fn draw<R: Rng + ?Sized>(&self, ids: &[String], count: usize, rng: &mut R) -> Vec<String> {
+        let mut questions: Vec<_> = ids
+            .iter()
+            .filter_map(|id| self.get(id))
+            .flat_map(|pack| pack.questions.iter().cloned())
+            .collect();
+        // the same question might be in many packs
+        questions.sort_unstable();
+        questions.dedup();
+        questions.shuffle(rng);
+        questions.truncate(count);
+        questions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(id: &str, questions: &[&str]) -> QuestionPack {
+        QuestionPack {
+            id: id.into(),
+            language: "en".into(),
+            tags: Vec::new(),
+            questions: questions.iter().map(|q| q.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn packs_are_loaded_from_toml_and_json() {
+        let dir = std::env::temp_dir().join(format!("eurus-packs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("a.toml"),
+            "id = \"a\"\nlanguage = \"en\"\ntags = [\"fun\"]\nquestions = [\"why?\"]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("b.json"),
+            r#"{"id": "b", "language": "pl", "questions": ["czemu?", "jak?"]}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("README.md"), "not a pack").unwrap();
+        let library = PackLibrary::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let packs = library.list();
+        assert_eq!(packs.len(), 2);
+        assert_eq!(packs[0].tags, vec!["fun".to_string()]);
+        assert_eq!(packs[1].language, "pl");
+        assert_eq!(packs[1].questions, 2);
+    }
+
+    #[test]
+    fn pack_ids_have_to_be_unique() {
+        assert!(PackLibrary::new(vec![pack("a", &[]), pack("a", &[])]).is_err());
+    }
+
+    #[test]
+    fn questions_are_drawn_from_chosen_packs() {
+        let library = PackLibrary::new(vec![
+            pack("a", &["1", "2", "3"]),
+            pack("b", &["3", "4"]),
+            pack("c", &["5"]),
+        ])
+        .unwrap();
+        let ids = vec!["a".to_string(), "b".to_string(), "nope".to_string()];
+        assert_eq!(library.find_missing(&ids), Some("nope"));
+        let mut drawn = library.draw(&ids, 10, &mut rand::thread_rng());
+        drawn.sort();
+        assert_eq!(drawn, vec!["1", "2", "3", "4"]);
+        assert_eq!(library.draw(&ids, 2, &mut rand::thread_rng()).len(), 2);
+    }
+
+    #[test]
+    fn draws_with_the_same_seed_are_the_same() {
+        use rand::SeedableRng;
+        let questions: Vec<_> = (0..20).map(|i| i.to_string()).collect();
+        let questions: Vec<_> = questions.iter().map(String::as_str).collect();
+        let library = PackLibrary::new(vec![pack("a", &questions)]).unwrap();
+        let ids = vec!["a".to_string()];
+        let draw = |seed| library.draw(&ids, 5, &mut rand_chacha::ChaCha8Rng::seed_from_u64(seed));
+        assert_eq!(draw(7), draw(7));
+        assert_ne!(draw(7), draw(8));
+    }
+}
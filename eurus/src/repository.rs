@@ -1,14 +1,28 @@
-use futures::Future;
-use mongodb::bson::doc;
+use futures::{Future, StreamExt};
+use mongodb::{
+    bson::{self, doc, Document},
+    options::FindOptions,
+};
 use tokio::sync::mpsc;
-use tracing::warn;
+use tracing::{debug, info, warn};
 
-use crate::{config::Config, db, room::model};
+use crate::{
+    config::Config,
+    db,
+    room::{
+        mode::{Credentials, GameKind, LogEntry},
+        model,
+    },
+    service,
+};
 
 pub type EntryId = [u8; 12];
+
+/// Credentials of the mqtt user, the password is in plain text
+/// as only its hash is stored in the database.
 pub struct UserEntry {
-    pub username: EntryId,
-    pub password: i64, // todo: bytes maybe?
+    pub username: String,
+    pub password: String,
 }
 
 pub struct RoomEntry {
@@ -16,25 +30,111 @@ pub struct RoomEntry {
     pub password: i64, // todo: change it to bytes maybe?
 }
 
+/// Room as it was persisted, the snapshot holds the state of its game mode.
+/// Credentials are kept apart from it, rooms created before that have none.
+pub struct SavedRoom {
+    pub id: EntryId,
+    pub mode: GameKind,
+    pub snapshot: String,
+    pub credentials: Option<Credentials>,
+}
+
 pub enum RepReq {
-    CreateRoom { players_limit: usize },
-    RemoveRoom { room_id: EntryId },
-    CreateRuntimeUser { room_id: EntryId },
-    CreatePlayerUser { room_id: EntryId },
+    CreateRoom {
+        players_limit: usize,
+        host_token: model::PlayerToken,
+        token_key: u64, // players' tokens are derived from it
+    },
+    AddPlayer {
+        room_id: EntryId,
+        password: i64,
+        token: Option<model::PlayerToken>, // given by an earlier join
+    },
+    ReleasePlayerSlot {
+        room_id: EntryId,
+    },
+    EvictPlayer {
+        room_id: EntryId,
+        player_id: model::PlayerId,
+        ban: bool,
+    },
+    RemoveRoom {
+        room_id: EntryId,
+    },
+    CreateRuntimeUser {
+        room_id: EntryId,
+    },
+    CreatePlayerUser {
+        room_id: EntryId,
+        player_id: model::PlayerId,
+    },
+    AddSpectator {
+        room_id: EntryId,
+        password: i64,
+        token: Option<model::PlayerToken>,
+        limit: usize, // how many spectators the room can have
+    },
+    CreateSpectatorUser {
+        room_id: EntryId,
+        spectator_id: model::SpectatorId,
+    },
+    SaveRoom {
+        mode: GameKind,
+        room_id: EntryId,
+        state: model::RoomState,
+        players_limit: usize, // how many players can get in, 0 once joining is closed
+        snapshot: String,     // json encoded model::Room
+    },
+    LoadRooms,
+    AppendEvents {
+        room_id: EntryId,
+        events: Vec<LogEntry<String>>, // json encoded events of the room's game mode
+    },
+    LoadEvents {
+        room_id: EntryId,
+    },
     Close,
 }
 
 pub enum RepResp {
     RoomCreated(RoomEntry),
+    PlayerAdded(model::PlayerId, model::PlayerToken),
+    PlayerSlotReleased,
+    PlayerEvicted,
+    SpectatorAdded(model::SpectatorId),
     RoomRemoved,
+    RoomSaved,
+    RoomsLoaded(Vec<SavedRoom>),
+    EventsAppended,
+    EventsLoaded(Vec<LogEntry<String>>),
     ClosingRepository,
     UserCreated(UserEntry),
 }
 
+#[derive(Debug)]
 pub enum RepError {
     ChannelClosed,
+    RoomNotFound,
+    RoomFull,
+    Banned,
+    DbError(mongodb::error::Error),
+    InternalError(String),
+}
+
+impl From<mongodb::error::Error> for RepError {
+    fn from(err: mongodb::error::Error) -> Self {
+        RepError::DbError(err)
+    }
 }
 
+// Has to match auth_opt_hasher_cost of the mosquitto-go-auth plugin.
+const BCRYPT_COST: u32 = 10;
+
+// Access levels as understood by mosquitto-go-auth.
+const ACL_READ: i32 = 1;
+const ACL_WRITE: i32 = 2;
+const ACL_SUBSCRIBE: i32 = 4;
+
 pub type RepReqChannel = mpsc::Sender<(RepReq, mpsc::Sender<Result<RepResp, RepError>>)>;
 pub struct DataRepository {
     conn: db::Connection,
@@ -69,23 +169,95 @@ impl DataRepository {
             async move {
                 while let Some((req, mut responder)) = rx.recv().await {
                     match req {
-                        RepReq::CreateRoom { players_limit } => {
-                            let rd = room_rep.create_room(players_limit).await;
+                        RepReq::CreateRoom {
+                            players_limit,
+                            host_token,
+                            token_key,
+                        } => {
+                            let rd = room_rep
+                                .create_room(players_limit, host_token, token_key)
+                                .await;
                             // let us just ignore an error here
                             let _ = responder.send(Ok(RepResp::RoomCreated(rd))).await;
                         }
+                        RepReq::AddPlayer {
+                            room_id,
+                            password,
+                            token,
+                        } => {
+                            let player = room_rep.add_player(room_id, password, token).await;
+                            let _ = responder
+                                .send(player.map(|(id, token)| RepResp::PlayerAdded(id, token)))
+                                .await;
+                        }
+                        RepReq::ReleasePlayerSlot { room_id } => {
+                            let res = room_rep.release_player_slot(room_id).await;
+                            let _ = responder
+                                .send(res.map(|_| RepResp::PlayerSlotReleased))
+                                .await;
+                        }
+                        RepReq::EvictPlayer {
+                            room_id,
+                            player_id,
+                            ban,
+                        } => {
+                            let res = room_rep.evict_player(room_id, player_id, ban).await;
+                            let _ = responder.send(res.map(|_| RepResp::PlayerEvicted)).await;
+                        }
                         RepReq::RemoveRoom { room_id } => {
-                            room_rep.remove_room(room_id).await;
+                            let res = room_rep.remove_room(room_id).await;
                             // let us just ignore an error here
-                            let _ = responder.send(Ok(RepResp::RoomRemoved)).await;
+                            let _ = responder.send(res.map(|_| RepResp::RoomRemoved)).await;
                         }
                         RepReq::CreateRuntimeUser { room_id } => {
                             let ud = room_rep.create_rt_user(room_id).await;
-                            let _ = responder.send(Ok(RepResp::UserCreated(ud))).await;
+                            let _ = responder.send(ud.map(RepResp::UserCreated)).await;
+                        }
+                        RepReq::CreatePlayerUser { room_id, player_id } => {
+                            let ud = room_rep.create_player_user(room_id, player_id).await;
+                            let _ = responder.send(ud.map(RepResp::UserCreated)).await;
                         }
-                        RepReq::CreatePlayerUser { room_id } => {
-                            let ud = room_rep.create_player_user(room_id).await;
-                            let _ = responder.send(Ok(RepResp::UserCreated(ud))).await;
+                        RepReq::AddSpectator {
+                            room_id,
+                            password,
+                            token,
+                            limit,
+                        } => {
+                            let spectator = room_rep
+                                .add_spectator(room_id, password, token, limit)
+                                .await;
+                            let _ = responder.send(spectator.map(RepResp::SpectatorAdded)).await;
+                        }
+                        RepReq::CreateSpectatorUser {
+                            room_id,
+                            spectator_id,
+                        } => {
+                            let ud = room_rep.create_spectator_user(room_id, spectator_id).await;
+                            let _ = responder.send(ud.map(RepResp::UserCreated)).await;
+                        }
+                        RepReq::SaveRoom {
+                            room_id,
+                            mode,
+                            state,
+                            players_limit,
+                            snapshot,
+                        } => {
+                            let res = room_rep
+                                .save_room(room_id, mode, state, players_limit, snapshot)
+                                .await;
+                            let _ = responder.send(res.map(|_| RepResp::RoomSaved)).await;
+                        }
+                        RepReq::LoadRooms => {
+                            let rooms = room_rep.load_rooms().await;
+                            let _ = responder.send(rooms.map(RepResp::RoomsLoaded)).await;
+                        }
+                        RepReq::AppendEvents { room_id, events } => {
+                            let res = room_rep.append_events(room_id, events).await;
+                            let _ = responder.send(res.map(|_| RepResp::EventsAppended)).await;
+                        }
+                        RepReq::LoadEvents { room_id } => {
+                            let events = room_rep.load_events(room_id).await;
+                            let _ = responder.send(events.map(RepResp::EventsLoaded)).await;
                         }
                         RepReq::Close => {
                             // Note that it does some cleanup after sending the message and whats
@@ -102,7 +274,14 @@ impl DataRepository {
         ))
     }
 
-    async fn create_room(&mut self, players_limit: usize) -> RoomEntry {
+    /// Credentials of the room are kept in its document
+    /// as they are left out of whatever gets logged in it.
+    async fn create_room(
+        &mut self,
+        players_limit: usize,
+        host_token: model::PlayerToken,
+        token_key: u64,
+    ) -> RoomEntry {
         let room_pass: i64 = rand::random();
         let insert_res = self
             .conn
@@ -112,6 +291,8 @@ impl DataRepository {
                     "room_pass": room_pass,
                     "players_limit": players_limit as i64,
                     "curr_players": 0_i32,
+                    "host_token": host_token as i64,
+                    "token_key": token_key as i64,
                 },
                 None,
             )
@@ -134,16 +315,414 @@ impl DataRepository {
         }
     }
 
-    async fn remove_room(&mut self, room: model::RoomId) {
-        // todo: implement
-        warn!("Removing room {}", base64::encode(&room));
+    /// Takes the next free player slot in the room returning its index,
+    /// which is then used as the player's id, along with the player's token.
+    /// Players who come with a token that got banned are not let in.
+    async fn add_player(
+        &mut self,
+        room: model::RoomId,
+        password: i64,
+        token: Option<model::PlayerToken>,
+    ) -> Result<(model::PlayerId, model::PlayerToken), RepError> {
+        // Filtering and incrementing in a single query so two players
+        // cannot take the last slot at the same time.
+        let limit = doc! { "$add": ["$players_limit", { "$ifNull": ["$slots_released", 0_i32] }] };
+        let mut filter = doc! {
+            "_id": room,
+            "room_pass": password,
+            "$expr": { "$lt": ["$curr_players", limit] },
+        };
+        if let Some(token) = token {
+            filter.insert("banned_tokens", doc! { "$ne": token as i64 });
+        }
+        let updated = self
+            .conn
+            .rooms_col
+            .find_one_and_update(filter, doc! { "$inc": { "curr_players": 1_i32 } }, None)
+            .await?;
+        if let Some(room_doc) = updated {
+            // by default we get the document from before the update
+            let curr_players = room_doc
+                .get_i32("curr_players")
+                .map_err(|err| RepError::InternalError(err.to_string()))?;
+            let player = curr_players as model::PlayerId;
+            // rooms created before tokens were derived use the default key
+            let token_key = room_doc.get_i64("token_key").unwrap_or(0) as u64;
+            return Ok((player, model::player_token(token_key, player)));
+        }
+        Err(self.refusal(room, password, token).await?)
+    }
+
+    /// Why the room did not let somebody in.
+    async fn refusal(
+        &mut self,
+        room: model::RoomId,
+        password: i64,
+        token: Option<model::PlayerToken>,
+    ) -> Result<RepError, RepError> {
+        let exists = self
+            .conn
+            .rooms_col
+            .find_one(doc! { "_id": room, "room_pass": password }, None)
+            .await?;
+        Ok(match exists {
+            Some(room_doc) if is_banned(&room_doc, token) => RepError::Banned,
+            Some(_) => RepError::RoomFull,
+            None => RepError::RoomNotFound,
+        })
+    }
+
+    /// Removes the player's mqtt user, a banned player's token
+    /// is not let into the room anymore.
+    async fn evict_player(
+        &mut self,
+        room: model::RoomId,
+        player: model::PlayerId,
+        ban: bool,
+    ) -> Result<(), RepError> {
+        let username = player_username(&service::encode_room_id(&room), &player.to_string());
+        self.conn
+            .users_col
+            .delete_many(doc! { "username": username.as_str() }, None)
+            .await?;
+        info!("removed mqtt user {}", username);
+        if !ban {
+            return Ok(());
+        }
+        let room_doc = self
+            .conn
+            .rooms_col
+            .find_one(doc! { "_id": room }, None)
+            .await?
+            .ok_or(RepError::RoomNotFound)?;
+        let token_key = room_doc.get_i64("token_key").unwrap_or(0) as u64;
+        let token = model::player_token(token_key, player) as i64;
+        self.conn
+            .rooms_col
+            .update_one(
+                doc! { "_id": room },
+                doc! { "$addToSet": { "banned_tokens": token } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Gives back the slot of a player who could not be let in after all.
+    /// Their id stays taken as somebody else might have joined meanwhile,
+    /// so the room lets one more player in instead.
+    async fn release_player_slot(&mut self, room: model::RoomId) -> Result<(), RepError> {
+        self.conn
+            .rooms_col
+            .update_one(
+                doc! { "_id": room },
+                doc! { "$inc": { "slots_released": 1_i32 } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Spectators are not limited, they just get the next id.
+    async fn add_spectator(
+        &mut self,
+        room: model::RoomId,
+        password: i64,
+        token: Option<model::PlayerToken>,
+        limit: usize,
+    ) -> Result<model::SpectatorId, RepError> {
+        // the field is missing until the first spectator comes
+        let spectators = doc! { "$ifNull": ["$curr_spectators", 0_i32] };
+        let mut filter = doc! {
+            "_id": room,
+            "room_pass": password,
+            "$expr": { "$lt": [spectators, limit as i64] },
+        };
+        if let Some(token) = token {
+            filter.insert("banned_tokens", doc! { "$ne": token as i64 });
+        }
+        let updated = self
+            .conn
+            .rooms_col
+            .find_one_and_update(filter, doc! { "$inc": { "curr_spectators": 1_i32 } }, None)
+            .await?;
+        match updated {
+            Some(room_doc) => Ok(room_doc.get_i32("curr_spectators").unwrap_or(0) as usize),
+            None => Err(self.refusal(room, password, token).await?),
+        }
+    }
+
+    /// Removes the room along with every mqtt user created for it.
+    async fn remove_room(&mut self, room: model::RoomId) -> Result<(), RepError> {
+        info!("Removing room {}", service::encode_room_id(&room));
+        let removed = self
+            .conn
+            .users_col
+            .delete_many(doc! { "room": room }, None)
+            .await?;
+        debug!("removed {} mqtt users", removed.deleted_count);
+        self.conn
+            .rooms_col
+            .delete_one(doc! { "_id": room }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn save_room(
+        &mut self,
+        room: model::RoomId,
+        mode: GameKind,
+        state: model::RoomState,
+        players_limit: usize,
+        snapshot: String,
+    ) -> Result<(), RepError> {
+        // state is kept next to the snapshot so we can query by it
+        let state =
+            bson::to_bson(&state).map_err(|err| RepError::InternalError(err.to_string()))?;
+        let mode = bson::to_bson(&mode).map_err(|err| RepError::InternalError(err.to_string()))?;
+        // the limit might have been changed by the host or a kicked player
+        // could have freed their slot, either way it is the runtime that knows
+        let update = doc! {
+            "$set": {
+                "mode": mode,
+                "state": state,
+                "snapshot": snapshot,
+                "players_limit": players_limit as i64,
+            }
+        };
+        self.conn
+            .rooms_col
+            .update_one(doc! { "_id": room }, update, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Loads every saved room that is still being played.
+    async fn load_rooms(&mut self) -> Result<Vec<SavedRoom>, RepError> {
+        let dead = bson::to_bson(&model::RoomState::Dead)
+            .map_err(|err| RepError::InternalError(err.to_string()))?;
+        let mut cursor = self
+            .conn
+            .rooms_col
+            .find(
+                doc! { "snapshot": { "$exists": true }, "state": { "$ne": dead } },
+                None,
+            )
+            .await?;
+        let mut rooms = Vec::new();
+        while let Some(room_doc) = cursor.next().await {
+            let room_doc = room_doc?;
+            match saved_room(&room_doc) {
+                Ok(room) => rooms.push(room),
+                Err(err) => warn!("skipping room {:?}: {}", room_doc.get("_id"), err),
+            }
+        }
+        Ok(rooms)
+    }
+
+    /// Events are never removed, not even with their room,
+    /// so finished games can still be looked into. They come
+    /// without the room's password and tokens, see `Credentials`.
+    async fn append_events(
+        &mut self,
+        room: model::RoomId,
+        events: Vec<LogEntry<String>>,
+    ) -> Result<(), RepError> {
+        let mut docs = Vec::with_capacity(events.len());
+        for entry in events {
+            let state = bson::to_bson(&entry.state)
+                .map_err(|err| RepError::InternalError(err.to_string()))?;
+            docs.push(doc! {
+                "room": room,
+                "seq": entry.seq as i64,
+                "state": state,
+                "event": entry.event,
+            });
+        }
+        self.conn.events_col.insert_many(docs, None).await?;
+        Ok(())
+    }
+
+    /// Loads every event logged in the room, oldest first.
+    async fn load_events(
+        &mut self,
+        room: model::RoomId,
+    ) -> Result<Vec<LogEntry<String>>, RepError> {
+        let opts = FindOptions::builder().sort(doc! { "seq": 1 }).build();
+        let mut cursor = self
+            .conn
+            .events_col
+            .find(doc! { "room": room }, opts)
+            .await?;
+        let mut events = Vec::new();
+        while let Some(event_doc) = cursor.next().await {
+            let event = log_entry(&event_doc?).map_err(RepError::InternalError)?;
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    /// Creates a user that can read everything players write
+    /// and write to every player in the room.
+    async fn create_rt_user(&mut self, room: model::RoomId) -> Result<UserEntry, RepError> {
+        let room_b64 = service::encode_room_id(&room);
+        let acls = vec![
+            acl(service::write_topic(&room_b64, "+"), ACL_READ),
+            acl(service::write_topic(&room_b64, "+"), ACL_SUBSCRIBE),
+            acl(service::read_topic(&room_b64, "+"), ACL_WRITE),
+            acl(service::will_topic(&room_b64, "+"), ACL_READ),
+            acl(service::will_topic(&room_b64, "+"), ACL_SUBSCRIBE),
+        ];
+        self.create_user(room, format!("room-rt-{}", room_b64), acls)
+            .await
     }
 
-    async fn create_rt_user(&mut self, _room: model::RoomId) -> UserEntry {
-        unimplemented!()
+    /// Creates a user that can only talk to the runtime through its own topics
+    /// and listen to what the runtime broadcasts to the whole room.
+    async fn create_player_user(
+        &mut self,
+        room: model::RoomId,
+        player: model::PlayerId,
+    ) -> Result<UserEntry, RepError> {
+        let room_b64 = service::encode_room_id(&room);
+        let player = player.to_string();
+        let acls = vec![
+            acl(service::read_topic(&room_b64, &player), ACL_READ),
+            acl(service::read_topic(&room_b64, &player), ACL_SUBSCRIBE),
+            acl(service::room_topic(&room_b64), ACL_READ),
+            acl(service::room_topic(&room_b64), ACL_SUBSCRIBE),
+            acl(service::write_topic(&room_b64, &player), ACL_WRITE),
+            acl(service::will_topic(&room_b64, &player), ACL_WRITE),
+        ];
+        self.create_user(room, player_username(&room_b64, &player), acls)
+            .await
     }
 
-    async fn create_player_user(&mut self, _room: model::RoomId) -> UserEntry {
-        unimplemented!()
+    /// Creates a user that can only listen to the room's broadcasts
+    /// and send audience votes, it cannot pretend to be a player.
+    async fn create_spectator_user(
+        &mut self,
+        room: model::RoomId,
+        spectator: model::SpectatorId,
+    ) -> Result<UserEntry, RepError> {
+        let room_b64 = service::encode_room_id(&room);
+        let spectator = service::spectator_user(spectator);
+        let acls = vec![
+            acl(service::room_topic(&room_b64), ACL_READ),
+            acl(service::room_topic(&room_b64), ACL_SUBSCRIBE),
+            acl(service::write_topic(&room_b64, &spectator), ACL_WRITE),
+        ];
+        self.create_user(room, format!("room-{}-{}", room_b64, spectator), acls)
+            .await
     }
+
+    async fn create_user(
+        &mut self,
+        room: model::RoomId,
+        username: String,
+        acls: Vec<Document>,
+    ) -> Result<UserEntry, RepError> {
+        let password = base64::encode(&rand::random::<[u8; 16]>());
+        let hash = hash_password(password.clone()).await?;
+        // The runtime's user gets recreated when its room is resumed
+        // as we only know the hash of its password.
+        self.conn
+            .users_col
+            .delete_many(doc! { "username": username.as_str() }, None)
+            .await?;
+        // mosquitto-go-auth keeps acls specific to the user in the user's
+        // document, the acls collection holds only the ones shared by everyone.
+        // The room field is not used by the plugin, it is there so we can find
+        // all of the room's users later on.
+        self.conn
+            .users_col
+            .insert_one(
+                doc! {
+                    "username": username.as_str(),
+                    "password": hash,
+                    "superuser": false,
+                    "acls": acls,
+                    "room": room,
+                },
+                None,
+            )
+            .await?;
+        info!("created mqtt user {}", username);
+        Ok(UserEntry { username, password })
+    }
+}
+
+fn player_username(room_b64: &str, player: &str) -> String {
+    format!("room-{}-{}", room_b64, player)
+}
+
+/// Whether the player coming with `token`, if they have one, got banned.
+fn is_banned(room_doc: &Document, token: Option<model::PlayerToken>) -> bool {
+    let token = match token {
+        Some(token) => token as i64,
+        None => return false,
+    };
+    match room_doc.get_array("banned_tokens") {
+        Ok(banned) => banned.iter().any(|banned| banned.as_i64() == Some(token)),
+        Err(_) => false,
+    }
+}
+
+fn acl(topic: String, acc: i32) -> Document {
+    doc! {
+        "topic": topic,
+        "acc": acc,
+    }
+}
+
+async fn hash_password(password: String) -> Result<String, RepError> {
+    // bcrypt is slow on purpose so we don't want to block the repository's task
+    match tokio::task::spawn_blocking(move || bcrypt::hash(password, BCRYPT_COST)).await {
+        Ok(Ok(hash)) => Ok(hash),
+        Ok(Err(err)) => Err(RepError::InternalError(err.to_string())),
+        Err(err) => Err(RepError::InternalError(err.to_string())),
+    }
+}
+
+fn saved_room(room_doc: &Document) -> Result<SavedRoom, String> {
+    let id = room_doc
+        .get_object_id("_id")
+        .map_err(|err| err.to_string())?
+        .bytes();
+    let snapshot = room_doc
+        .get_str("snapshot")
+        .map_err(|err| err.to_string())?
+        .to_owned();
+    // rooms saved before game modes were introduced do not have one
+    let mode = match room_doc.get("mode") {
+        Some(mode) => bson::from_bson(mode.clone()).map_err(|err| err.to_string())?,
+        None => GameKind::default(),
+    };
+    Ok(SavedRoom {
+        id,
+        mode,
+        snapshot,
+        credentials: credentials(room_doc),
+    })
+}
+
+fn credentials(room_doc: &Document) -> Option<Credentials> {
+    Some(Credentials {
+        password: room_doc.get_i64("room_pass").ok()?,
+        host_token: room_doc.get_i64("host_token").ok()? as model::PlayerToken,
+        token_key: room_doc.get_i64("token_key").ok()? as u64,
+    })
+}
+
+fn log_entry(event_doc: &Document) -> Result<LogEntry<String>, String> {
+    let seq = event_doc.get_i64("seq").map_err(|err| err.to_string())? as u64;
+    let state = match event_doc.get("state") {
+        Some(state) => bson::from_bson(state.clone()).map_err(|err| err.to_string())?,
+        None => return Err("event without a state".into()),
+    };
+    let event = event_doc
+        .get_str("event")
+        .map_err(|err| err.to_string())?
+        .to_owned();
+    Ok(LogEntry { seq, state, event })
 }
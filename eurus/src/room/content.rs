@@ -0,0 +1,149 @@
+//! Checks of what players write before it gets into the room.
+//!
+//! Questions and answers are normalized first, so the same text typed
+//! a bit differently is stored and compared the same way: it is put
+//! in the NFC form, surrounding whitespace is trimmed and any run of
+//! whitespace inside is turned into a single space. Then it has to fit
+//! within the limits from the config and must not contain banned words.
+//! Words are compared in the NFKC form, so banned ones written with look-alike
+//! characters, like the fullwidth ones, are caught as well.
+use std::collections::HashSet;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{config::Content, message::ErrResponse};
+
+pub struct ContentFilter {
+    config: Content,
+    banned: HashSet<String>,
+}
+
+impl ContentFilter {
+    pub fn new(config: Content) -> Self {
+        let banned = config
+            .banned_words
+            .iter()
+            .map(|word| fold(&normalize(word)))
+            .filter(|word| !word.is_empty())
+            .collect();
+        Self { config, banned }
+    }
+
+    pub fn config(&self) -> &Content {
+        &self.config
+    }
+
+    /// Returns the normalized question or why it got rejected.
+    pub fn question(&self, content: &str) -> Result<String, ErrResponse> {
+        self.check(content, self.config.max_question_len)
+    }
+
+    /// Returns the normalized answer or why it got rejected.
+    pub fn answer(&self, content: &str) -> Result<String, ErrResponse> {
+        self.check(content, self.config.max_answer_len)
+    }
+
+    fn check(&self, content: &str, max_len: usize) -> Result<String, ErrResponse> {
+        let content = normalize(content);
+        if content.is_empty() {
+            return Err(ErrResponse::EmptyContent);
+        }
+        if content.chars().count() > max_len {
+            return Err(ErrResponse::ContentTooLong);
+        }
+        if fold(&content)
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|word| self.banned.contains(word))
+        {
+            return Err(ErrResponse::InappropriateContent);
+        }
+        Ok(content)
+    }
+}
+
+pub fn normalize(content: &str) -> String {
+    let content: String = content.nfc().collect();
+    content.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Compatibility form of `content` in lowercase, used only to look for
+/// banned words as it loses some of the formatting.
+fn fold(content: &str) -> String {
+    content.nfkc().collect::<String>().to_lowercase()
+}
+
+/// Whether the normalized `content` is already among `existing`,
+/// letter case does not matter.
+pub fn is_duplicate<'a, I>(content: &str, existing: I) -> bool
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let content = content.to_lowercase();
+    existing
+        .into_iter()
+        .any(|other| other.to_lowercase() == content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(banned: &[&str]) -> ContentFilter {
+        ContentFilter::new(Content {
+            max_question_len: 10,
+            max_answer_len: 5,
+            banned_words: banned.iter().map(|w| w.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn content_is_normalized() {
+        // "e" followed by a combining acute accent
+        assert_eq!(
+            normalize("  cafe\u{301} \t\n au  lait "),
+            "caf\u{e9} au lait"
+        );
+        assert_eq!(
+            filter(&[]).question(" why\u{a0}not? "),
+            Ok("why not?".into())
+        );
+    }
+
+    #[test]
+    fn empty_and_long_content_is_rejected() {
+        let filter = filter(&[]);
+        assert_eq!(filter.answer(""), Err(ErrResponse::EmptyContent));
+        assert_eq!(filter.answer(" \t\n"), Err(ErrResponse::EmptyContent));
+        assert_eq!(filter.answer("abcdef"), Err(ErrResponse::ContentTooLong));
+        // limits count characters, not bytes
+        assert_eq!(filter.answer("żółćę"), Ok("żółćę".into()));
+        assert_eq!(filter.question("abcdef"), Ok("abcdef".into()));
+    }
+
+    #[test]
+    fn banned_words_are_rejected() {
+        let filter = filter(&["Darn", " heck "]);
+        assert_eq!(
+            filter.question("oh, DARN!"),
+            Err(ErrResponse::InappropriateContent)
+        );
+        assert_eq!(
+            filter.answer("heck"),
+            Err(ErrResponse::InappropriateContent)
+        );
+        // fullwidth letters are the same words
+        assert_eq!(
+            filter.question("\u{ff44}\u{ff41}\u{ff52}\u{ff4e}"),
+            Err(ErrResponse::InappropriateContent)
+        );
+        // only whole words count
+        assert_eq!(filter.question("darnit"), Ok("darnit".into()));
+    }
+
+    #[test]
+    fn duplicates_ignore_letter_case() {
+        let existing = ["Why?", "how?"];
+        assert!(is_duplicate("why?", existing.iter().copied()));
+        assert!(!is_duplicate("when?", existing.iter().copied()));
+    }
+}
@@ -1,2 +1,5 @@
+pub mod content;
+pub mod mode;
 pub mod model;
 pub mod runtime;
+pub mod scoring;
@@ -0,0 +1,117 @@
+//! Game modes that can be played in a room.
+//!
+//! The room loop in `service` only takes care of the transport, timers
+//! and persistence, the game itself is up to the mode. Every mode has its
+//! own state, which is what gets persisted, and its own requests and
+//! responses, which are what players exchange with the runtime over mqtt.
+use std::fmt::Debug;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+    config::Config,
+    packs::PackLibrary,
+    room::model::{PlayerId, PlayerToken, RoomId, RoomState},
+    service::{dto::NewRoomReq, Command},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum GameKind {
+    #[default]
+    QuestionsAndAnswers,
+}
+
+/// What lets the players and the host into a room, given out on its creation.
+/// Credentials are left out of the room's log, so whoever can read
+/// the log cannot take over the room, and given back when it gets replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub password: i64,
+    pub host_token: PlayerToken,
+    pub token_key: u64, // players' tokens are derived from it
+}
+
+/// What the server needs to know about a game being played.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub state: RoomState,
+    pub players: usize,
+    pub players_limit: usize,
+    pub slots_freed: usize, // by players who left the room after joining it
+    pub rounds_limit: usize,
+    pub round: Option<usize>, // number of the round being played
+}
+
+/// Something that happened in a room, `seq` numbers the events
+/// of a single room in the order they happened.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry<E> {
+    pub seq: u64,
+    pub state: RoomState, // state of the room right after the event
+    pub event: E,
+}
+
+/// Player thrown out of the room, whose access to it has to be revoked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Eviction {
+    pub player: PlayerId,
+    pub banned: bool, // they are not allowed to join the room ever again
+}
+
+pub(crate) trait ModeRequest: DeserializeOwned + Debug + Send {
+    /// Request made on behalf of a player whose connection dropped.
+    fn connection_lost() -> Self;
+}
+
+pub(crate) trait ModeResponse: Serialize + Debug + Send + Sync + Sized {
+    fn runtime_started() -> Self;
+    fn server_shutting_down() -> Self;
+    /// Runtime's last will, published to the whole room by the broker.
+    fn runtime_lost() -> Self;
+    /// Splits the response into its recipient, `None` meaning
+    /// the whole room, and the message that should be sent.
+    fn route(self) -> (Option<PlayerId>, Self);
+}
+
+pub(crate) trait GameMode: Send + Sync + Sized + 'static {
+    type State: Serialize + DeserializeOwned + Send;
+    type Request: ModeRequest;
+    type Response: ModeResponse;
+    /// What gets written to the room's log, replaying the events
+    /// has to bring the state back to what it was.
+    type Event: Serialize + DeserializeOwned + Send + Sync;
+
+    const KIND: GameKind;
+
+    /// State of a brand new room.
+    fn setup(
+        id: RoomId,
+        credentials: &Credentials,
+        seed: u64,
+        req: &NewRoomReq,
+        packs: &PackLibrary,
+    ) -> Self::State;
+    fn from_state(state: Self::State, config: Config) -> Self;
+    /// Rebuilds the state out of every event logged in the room,
+    /// `None` if they do not make up a whole game.
+    fn replay(events: Vec<Self::Event>, credentials: &Credentials) -> Option<Self::State>;
+    fn state(&self) -> &Self::State;
+    fn summary(&self) -> Summary;
+    /// `sender` is either "rt" for the room's own topic or the player's id.
+    fn process_msg(&mut self, sender: &str, msg: Self::Request) -> Command<Self::Response>;
+    fn process_timer(&mut self, now: Instant) -> Command<Self::Response>;
+    /// When `process_timer` should be called next, if at all.
+    fn wakeup(&self) -> Option<Instant>;
+    /// Returns true if the state has changed since the last call,
+    /// so the caller knows when it needs to be persisted again.
+    fn take_changed(&mut self) -> bool;
+    /// Events logged since the last call, oldest first.
+    fn take_log(&mut self) -> Vec<LogEntry<Self::Event>>;
+    /// Players thrown out since the last call.
+    fn take_evicted(&mut self) -> Vec<Eviction>;
+
+    fn is_finished(&self) -> bool {
+        self.summary().state == RoomState::Dead
+    }
+}
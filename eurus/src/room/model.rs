@@ -1,12 +1,27 @@
 use crate::repository::EntryId;
+use crate::room::scoring::{self, ScoringRule};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 pub type QuestionId = usize;
 pub type RoomId = EntryId;
 pub type PlayerId = usize;
 pub type PlayerToken = usize;
+pub type SpectatorId = usize;
+pub type TeamId = usize;
 pub type AnswerId = usize;
 
+/// Token the player gets when they join the room over http. It is derived
+/// from the room's key so the runtime can tell it is the one they were given.
+pub fn player_token(key: u64, player: PlayerId) -> PlayerToken {
+    let mut rng = ChaCha8Rng::seed_from_u64(key);
+    rng.set_stream(player as u64);
+    rng.gen()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Room {
     pub id: RoomId,           // on room creation
     pub pass: i64,            // on room creation
@@ -17,6 +32,38 @@ pub struct Room {
     pub curr_round: Option<Round>,
     pub past_rounds: Vec<Round>,
     pub state: RoomState,
+    #[serde(default = "scoring::default_rules")]
+    pub scoring: Vec<ScoringRule>, // on room creation
+    #[serde(default)]
+    pub host_token: Option<PlayerToken>, // on room creation
+    #[serde(default)]
+    pub token_key: u64, // on room creation, players' tokens are derived from it
+    #[serde(default)]
+    pub banned: Vec<PlayerId>,
+    #[serde(default)]
+    pub kicked: Vec<PlayerId>, // whatever they still send is ignored
+    #[serde(default)]
+    pub teams: usize, // on room creation, 0 if everybody plays on their own
+    #[serde(default)]
+    pub team_answers: bool, // on room creation
+    #[serde(default)]
+    pub tie_break: TieBreak, // on room creation
+    #[serde(default)]
+    pub sudden_death: Vec<PlayerId>, // players still tied, empty unless in sudden death
+    #[serde(default)]
+    pub pack_questions: Option<usize>, // on room creation, questions every game takes from packs
+    #[serde(default)]
+    pub spare_questions: Vec<Question>, // pack questions not asked yet, for sudden death and rematches
+    #[serde(default)]
+    pub slots_freed: usize, // players who left the room after joining it
+    #[serde(default)]
+    pub past_games: Vec<GameRecord>, // games finished before a rematch
+    #[serde(default)]
+    pub events_logged: u64, // sequence number of the next logged event
+    #[serde(default)]
+    pub seed: u64, // on room creation, everything random in the room comes from it
+    #[serde(default)]
+    pub draws: u64, // how many times something random was drawn
 }
 
 impl Room {
@@ -31,44 +78,137 @@ impl Room {
             past_rounds: Vec::new(),
             curr_round: None,
             state: RoomState::AcceptingPlayers,
+            scoring: scoring::default_rules(),
+            host_token: None,
+            token_key: 0,
+            banned: Vec::new(),
+            kicked: Vec::new(),
+            teams: 0,
+            team_answers: false,
+            tie_break: TieBreak::default(),
+            sudden_death: Vec::new(),
+            pack_questions: None,
+            spare_questions: Vec::new(),
+            slots_freed: 0,
+            past_games: Vec::new(),
+            events_logged: 0,
+            seed: 0,
+            draws: 0,
         }
     }
+
+    /// Generator for the next random draw. Each draw gets its own stream
+    /// of the generator seeded with the room's seed, so a room with the same
+    /// seed and the same events draws the same numbers while rooms with
+    /// different seeds do not share any of their draws.
+    pub fn rng(&mut self) -> ChaCha8Rng {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        rng.set_stream(self.draws);
+        self.draws += 1;
+        rng
+    }
+
+    pub fn player(&self, id: PlayerId) -> Option<&Player> {
+        self.players.iter().find(|p| p.id == id)
+    }
+
+    pub fn player_mut(&mut self, id: PlayerId) -> Option<&mut Player> {
+        self.players.iter_mut().find(|p| p.id == id)
+    }
+
+    pub fn has_player(&self, id: PlayerId) -> bool {
+        self.player(id).is_some()
+    }
+
+    /// Players who are not tied in sudden death only get to vote.
+    pub fn sits_out(&self, player: PlayerId) -> bool {
+        !self.sudden_death.is_empty() && !self.sudden_death.contains(&player)
+    }
+
+    pub fn team_of(&self, id: PlayerId) -> Option<TeamId> {
+        self.player(id).and_then(|p| p.team)
+    }
+
+    /// Whether the player has nothing left to answer in the round,
+    /// with team answers one answer is enough for the whole team
+    /// and those sitting sudden death out are not expected to answer.
+    pub fn has_answered(&self, round: &Round, player: PlayerId) -> bool {
+        if self.sits_out(player) {
+            return true;
+        }
+        let team = self.team_of(player);
+        round.answers.contains_key(&player)
+            || (self.team_answers
+                && team.is_some()
+                && round.answers.values().any(|a| a.team == team))
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum RoomState {
     AcceptingPlayers,
     AcceptingQuestions,
     Playing,
+    Finished, // the game is over but the host can still start a rematch
     Dead,
 }
 
+/// How the game ends when there is a tie for the first place.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum TieBreak {
+    #[default]
+    SharedVictory, // everybody tied wins
+    SuddenDeath,    // extra rounds of pack questions for the tied players
+    EarliestAnswer, // whoever was first to answer the last round wins
+}
+
+/// Results of a game played in the room before a rematch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameRecord {
+    pub rounds: Vec<Round>,
+    pub players: Vec<Player>, // with the points they ended the game with
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub id: PlayerId,
     pub token: PlayerToken,
     pub name: String,
-    pub points: usize,
+    pub points: i64,
+    #[serde(default)]
+    pub team: Option<TeamId>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Question {
     pub id: QuestionId,
-    pub player_id: PlayerId, // who made this question
+    pub player_id: Option<PlayerId>, // who made this question, none if taken from a pack
     pub content: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Round {
     pub round_num: usize,
     pub state: RoundState,
     pub question: Question,
     pub answers: HashMap<PlayerId, Answer>,
     pub polls: HashMap<PlayerId, AnswerId>,
+    #[serde(default)]
+    pub audience_polls: HashMap<SpectatorId, AnswerId>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Answer {
     pub id: AnswerId,
     pub player_id: PlayerId, // who answered
     pub content: String,
+    #[serde(default)]
+    pub team: Option<TeamId>, // the author's team when the answer was given
+    #[serde(default)]
+    pub received: usize, // how many answers came in before this one
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum RoundState {
     AcceptingAnswers,
     Polling,
@@ -1,30 +1,2733 @@
-use tracing::info;
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    time::Duration,
+};
 
-use crate::room::model::Room;
-use crate::{config::Config, message, service};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
 
+use crate::room::{
+    content::{self, ContentFilter},
+    mode::{
+        Credentials, Eviction, GameKind, GameMode, LogEntry, ModeRequest, ModeResponse, Summary,
+    },
+    model::{
+        self, Answer, AnswerId, GameRecord, Player, PlayerId, PlayerToken, Question, QuestionId,
+        Room, RoomId, RoomState, Round, RoundState, SpectatorId, TeamId, TieBreak,
+    },
+    scoring::{self, Breakdown, RoundContext, ScoringPolicy, ScoringRule},
+};
+use crate::{
+    config::{Config, Content, IdlePolicy, Timers},
+    message::{
+        AnswerInfo, AnswerResult, ErrResponse, HostCommand, Phase, PlayerInfo, PlayerScore,
+        Request, Response, RoomSnapshot, ScoreTable, TeamScore,
+    },
+    packs::PackLibrary,
+    service::{self, dto::NewRoomReq},
+};
+
+/// Answer ids are drawn from 0 up to this once polling starts.
+const ANSWER_ID_RANGE: AnswerId = 1 << 30;
+
+/// Sudden death rounds played at most before the tied players share the win.
+const MAX_SUDDEN_DEATH_ROUNDS: usize = 3;
+
+/// The question and answer game, players come up with questions,
+/// answer them and vote for the best answer in each round.
 pub struct Runtime {
-    _rd: Room,
-    _config: Config,
+    rd: Room,
+    changed: bool,
+    timer: Option<Timer>,
+    // disconnected players and when they are going to be removed
+    away: HashMap<PlayerId, Instant>,
+    scoring: Vec<Box<dyn ScoringPolicy>>,
+    timers: Timers,
+    content: ContentFilter,
+    log: Vec<LogEntry<RoomEvent>>,
+    evicted: Vec<Eviction>,
+    // the room is being rebuilt out of its log
+    replaying: bool,
+}
+
+/// Everything that moved the room from one state to another.
+/// Requests that got rejected or changed nothing are not logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum RoomEvent {
+    // the runtime got created or resumed with the room as it was then
+    Started {
+        room: Box<Room>,
+        timers: Timers,
+        #[serde(default)]
+        content: Content,
+    },
+    Request {
+        sender: String,
+        request: Request,
+    },
+    PhaseExpired {
+        phase: Phase,
+    },
+    RejoinExpired {
+        player: PlayerId,
+    },
+}
+
+impl RoomEvent {
+    /// The event without the room's password and tokens. Whether
+    /// the room has a host is kept as it changes how the game goes.
+    fn redacted(self) -> Self {
+        match self {
+            RoomEvent::Started {
+                mut room,
+                timers,
+                content,
+            } => {
+                room.pass = 0;
+                room.host_token = room.host_token.map(|_| 0);
+                room.token_key = 0;
+                for player in room.players.iter_mut() {
+                    player.token = 0;
+                }
+                RoomEvent::Started {
+                    room,
+                    timers,
+                    content,
+                }
+            }
+            RoomEvent::Request { sender, request } => {
+                let request = match request {
+                    Request::JoinRoom { name, .. } => Request::JoinRoom { name, token: 0 },
+                    Request::Rejoin { .. } => Request::Rejoin { token: 0 },
+                    Request::Host { cmd, .. } => Request::Host { token: 0, cmd },
+                    request => request,
+                };
+                RoomEvent::Request { sender, request }
+            }
+            event => event,
+        }
+    }
+}
+
+/// Deadline of the phase currently being played.
+struct Timer {
+    phase: Phase,
+    round_num: Option<usize>,
+    deadline: Instant,
+    next_tick: Option<Instant>,
 }
 
 impl Runtime {
     pub fn new(rd: Room, config: Config) -> Self {
-        Self {
-            _rd: rd,
-            _config: config,
+        Self::with_settings(rd, config.timers, config.content)
+    }
+
+    pub fn with_settings(rd: Room, timers: Timers, content: Content) -> Self {
+        let scoring = rd.scoring.iter().map(ScoringRule::policy).collect();
+        let mut rt = Self {
+            rd,
+            changed: false,
+            timer: None,
+            away: HashMap::new(),
+            scoring,
+            timers,
+            content: ContentFilter::new(content),
+            log: Vec::new(),
+            evicted: Vec::new(),
+            replaying: false,
+        };
+        rt.record(RoomEvent::Started {
+            room: Box::new(rt.rd.clone()),
+            timers: rt.timers.clone(),
+            content: rt.content.config().clone(),
+        });
+        // a resumed room has to get its timer back,
+        // players will learn about it with the next tick
+        rt.sync_timer(Instant::now());
+        rt
+    }
+
+    pub fn room(&self) -> &Room {
+        &self.rd
+    }
+
+    fn record(&mut self, event: RoomEvent) {
+        let seq = self.rd.events_logged;
+        self.rd.events_logged += 1;
+        self.log.push(LogEntry {
+            seq,
+            state: self.rd.state,
+            event: event.redacted(),
+        });
+    }
+
+    /// Whether the player's token is valid, tokens are not checked
+    /// when replaying as they are left out of the log.
+    fn accepts_token(&self, token: PlayerToken, valid: Option<PlayerToken>) -> bool {
+        self.replaying || valid == Some(token)
+    }
+
+    fn current_phase(&self) -> Option<(Phase, Option<usize>)> {
+        match self.rd.state {
+            RoomState::AcceptingQuestions => Some((Phase::Questions, None)),
+            RoomState::Playing => self.rd.curr_round.as_ref().map(|round| match round.state {
+                RoundState::AcceptingAnswers => (Phase::Answers, Some(round.round_num)),
+                RoundState::Polling => (Phase::Polling, Some(round.round_num)),
+            }),
+            RoomState::Finished => Some((Phase::Rematch, None)),
+            RoomState::AcceptingPlayers | RoomState::Dead => None,
+        }
+    }
+
+    /// Starts a new timer if the phase has changed since the last one was started.
+    fn sync_timer(&mut self, now: Instant) -> Vec<Response> {
+        let (phase, round_num) = match self.current_phase() {
+            Some(val) => val,
+            None => {
+                self.timer = None;
+                return Vec::new();
+            }
+        };
+        if let Some(timer) = &self.timer {
+            if timer.phase == phase && timer.round_num == round_num {
+                return Vec::new();
+            }
+        }
+        let timers = &self.timers;
+        let limit = match phase {
+            Phase::Questions => timers.questions_secs,
+            Phase::Answers => timers.answers_secs,
+            Phase::Polling => timers.polling_secs,
+            Phase::Rematch => timers.rematch_secs,
+        };
+        if limit == 0 {
+            self.timer = None;
+            return Vec::new();
+        }
+        let next_tick = match timers.tick_secs {
+            0 => None,
+            secs => Some(now + Duration::from_secs(secs)),
+        };
+        self.timer = Some(Timer {
+            phase,
+            round_num,
+            deadline: now + Duration::from_secs(limit),
+            next_tick,
+        });
+        vec![Response::TimeLeft { phase, secs: limit }]
+    }
+
+    fn expire(&mut self, phase: Phase) -> Vec<Response> {
+        info!("{:?} phase ran out of time", phase);
+        let mut resps = Vec::new();
+        let expired = self.current_phase();
+        if self.timers.idle_policy == IdlePolicy::Kick {
+            for player in self.idle_players(phase) {
+                info!("kicking idle player {}", player);
+                resps.extend(self.remove_player(player));
+            }
+        }
+        // removing the idle players might have moved the game forward already,
+        // even to the same phase of the next round
+        if expired.map(|(current, _)| current) == Some(phase) && self.current_phase() == expired {
+            resps.extend(match phase {
+                Phase::Questions => self.start_game(),
+                Phase::Answers => self.start_polling(),
+                Phase::Polling => self.finish_round(),
+                Phase::Rematch => self.close_room(),
+            });
+        }
+        self.record(RoomEvent::PhaseExpired { phase });
+        resps
+    }
+
+    fn idle_players(&self, phase: Phase) -> Vec<PlayerId> {
+        let round = match (phase, &self.rd.curr_round) {
+            (Phase::Answers, Some(round)) | (Phase::Polling, Some(round)) => round,
+            _ => return Vec::new(),
+        };
+        self.rd
+            .players
+            .iter()
+            .map(|p| p.id)
+            .filter(|id| match phase {
+                Phase::Answers => !self.rd.has_answered(round, *id),
+                _ => !done_voting(round, *id, self.rd.team_of(*id)),
+            })
+            .collect()
+    }
+
+    fn process_global_msg(&mut self, msg: Request) -> Vec<Response> {
+        match msg {
+            Request::GetRoomState => vec![self.room_state()],
+            msg => {
+                warn!("unsupported global msg {:?}", msg);
+                Vec::new()
+            }
+        }
+    }
+
+    fn process_player_msg(&mut self, player: PlayerId, msg: Request) -> Vec<Response> {
+        // kicked players stay connected until their session ends
+        if self.rd.kicked.contains(&player) {
+            debug!("ignoring msg from kicked player {}", player);
+            return Vec::new();
+        }
+        let joining = matches!(msg, Request::JoinRoom { .. });
+        if !joining && !self.rd.has_player(player) {
+            return err(player, ErrResponse::NotJoined);
+        }
+        // whoever sends anything else is connected again, even without rejoining
+        let active = !matches!(msg, Request::Disconnecting | Request::Rejoin { .. });
+        if active && self.away.remove(&player).is_some() {
+            info!("player {} is back", player);
+        }
+        match (self.rd.state, msg) {
+            (_, Request::JoinRoom { name, token }) => self.add_player(player, name, token),
+            (_, Request::GetRoomState) => priv_resp(player, self.room_state()),
+            (_, Request::Rejoin { token }) => self.rejoin(player, token),
+            (state, Request::Host { token, cmd }) => {
+                if !self.accepts_token(token, self.rd.host_token) {
+                    return err(player, ErrResponse::NotHost);
+                }
+                self.process_host_cmd(player, state, cmd)
+            }
+            (_, Request::Disconnecting) => self.disconnect(player, Instant::now()),
+            (RoomState::AcceptingQuestions, Request::AddQuestion { content }) => {
+                self.add_question(player, content)
+            }
+            (RoomState::Playing, Request::AddAnswer { content }) => {
+                self.add_answer(player, content)
+            }
+            (RoomState::Playing, Request::SelectAnswer { answer }) => {
+                self.select_answer(player, answer)
+            }
+            (state, msg) => {
+                debug!("unexpected msg {:?} in state {:?}", msg, state);
+                err(player, ErrResponse::UnexpectedRequest)
+            }
+        }
+    }
+
+    /// Spectators can only vote and only if the audience counts for something,
+    /// they have no topic of their own to hear back from the runtime on.
+    fn process_spectator_msg(&mut self, spectator: SpectatorId, msg: Request) -> Vec<Response> {
+        let audience_counts = self
+            .rd
+            .scoring
+            .iter()
+            .any(|rule| matches!(rule, ScoringRule::AudienceVotes(_)));
+        let answer = match msg {
+            Request::SelectAnswer { answer } if audience_counts => answer,
+            msg => {
+                debug!("ignoring msg {:?} from spectator {}", msg, spectator);
+                return Vec::new();
+            }
+        };
+        match self.rd.curr_round.as_mut() {
+            Some(round)
+                if round.state == RoundState::Polling
+                    && round.answers.values().any(|a| a.id == answer) =>
+            {
+                if let Entry::Vacant(vote) = round.audience_polls.entry(spectator) {
+                    vote.insert(answer);
+                    self.changed = true;
+                }
+            }
+            _ => debug!("spectator {} cannot vote for {} now", spectator, answer),
+        }
+        Vec::new()
+    }
+
+    fn process_host_cmd(
+        &mut self,
+        host: PlayerId,
+        state: RoomState,
+        cmd: HostCommand,
+    ) -> Vec<Response> {
+        info!("host command {:?}", cmd);
+        match (state, cmd) {
+            (RoomState::AcceptingPlayers, HostCommand::StartGame)
+                if !self.rd.players.is_empty() =>
+            {
+                self.close_joining()
+            }
+            (RoomState::AcceptingQuestions, HostCommand::StartGame)
+                if !self.rd.questions.is_empty() =>
+            {
+                self.start_game()
+            }
+            (_, HostCommand::Kick { player }) => self.kick(host, player, false),
+            (_, HostCommand::Ban { player }) => self.kick(host, player, true),
+            (RoomState::AcceptingQuestions, HostCommand::RejectQuestion { question }) => {
+                self.reject_question(host, question)
+            }
+            (RoomState::Playing, HostCommand::SkipRound) => self.skip_round(),
+            (
+                RoomState::AcceptingPlayers,
+                HostCommand::ChangeLimits {
+                    players_limit,
+                    rounds_limit,
+                },
+            ) => self.change_limits(host, players_limit, rounds_limit),
+            (RoomState::Finished, HostCommand::EndGame) => self.close_room(),
+            (_, HostCommand::EndGame) => self.finish_game(),
+            (
+                RoomState::Finished,
+                HostCommand::Rematch {
+                    players_limit,
+                    rounds_limit,
+                },
+            ) => self.rematch(host, players_limit, rounds_limit),
+            (RoomState::AcceptingPlayers, HostCommand::AssignTeam { player, team }) => {
+                self.assign_team(host, player, team)
+            }
+            (state, cmd) => {
+                debug!("unexpected host command {:?} in state {:?}", cmd, state);
+                err(host, ErrResponse::UnexpectedRequest)
+            }
+        }
+    }
+
+    fn room_state(&self) -> Response {
+        Response::RoomState(RoomSnapshot::from(&self.rd))
+    }
+
+    fn add_player(&mut self, player: PlayerId, name: String, token: PlayerToken) -> Vec<Response> {
+        if self.rd.state != RoomState::AcceptingPlayers {
+            return err(player, ErrResponse::UnexpectedRequest);
+        }
+        // only the token given out over http lets the player in
+        let valid = model::player_token(self.rd.token_key, player);
+        if !self.accepts_token(token, Some(valid)) {
+            return err(player, ErrResponse::InvalidToken);
+        }
+        if self.rd.has_player(player) {
+            return err(player, ErrResponse::AlreadyJoined);
+        }
+        if self.rd.banned.contains(&player) {
+            return err(player, ErrResponse::Banned);
+        }
+        if self.rd.players.len() >= self.rd.players_limit {
+            return err(player, ErrResponse::RoomFull);
+        }
+        let team = self.smallest_team();
+        self.rd.players.push(Player {
+            id: player,
+            token,
+            name: name.clone(),
+            points: 0,
+            team,
+        });
+        self.changed = true;
+        let mut resps = vec![Response::NewPlayerJoined(PlayerInfo {
+            id: player,
+            name,
+            team,
+        })];
+        if self.rd.players.len() == self.rd.players_limit {
+            info!("room is full");
+            resps.extend(self.close_joining());
+        }
+        resps
+    }
+
+    /// Team with the fewest players, `None` when playing without teams.
+    fn smallest_team(&self) -> Option<TeamId> {
+        let members = |team| {
+            self.rd
+                .players
+                .iter()
+                .filter(|p| p.team == Some(team))
+                .count()
+        };
+        (0..self.rd.teams).min_by_key(|team| members(*team))
+    }
+
+    fn assign_team(&mut self, host: PlayerId, player: PlayerId, team: TeamId) -> Vec<Response> {
+        if team >= self.rd.teams {
+            return err(host, ErrResponse::NoSuchTeam);
+        }
+        match self.rd.player_mut(player) {
+            Some(p) => p.team = Some(team),
+            None => return err(host, ErrResponse::NoSuchPlayer),
+        }
+        self.changed = true;
+        vec![Response::TeamAssigned { player, team }]
+    }
+
+    fn close_joining(&mut self) -> Vec<Response> {
+        info!("accepting questions");
+        self.rd.state = RoomState::AcceptingQuestions;
+        self.changed = true;
+        // questions might have been taken from packs already
+        if self.rd.questions.len() >= self.rd.rounds_limit {
+            return self.start_game();
+        }
+        Vec::new()
+    }
+
+    fn rejoin(&mut self, player: PlayerId, token: PlayerToken) -> Vec<Response> {
+        if self.rd.banned.contains(&player) {
+            return err(player, ErrResponse::Banned);
+        }
+        if !self.accepts_token(token, self.rd.player(player).map(|p| p.token)) {
+            return err(player, ErrResponse::InvalidToken);
+        }
+        if self.away.remove(&player).is_some() {
+            info!("player {} is back", player);
+        }
+        priv_resp(player, self.room_state())
+    }
+
+    /// Gives the player some time to come back before they are removed.
+    fn disconnect(&mut self, player: PlayerId, now: Instant) -> Vec<Response> {
+        match self.timers.rejoin_grace_secs {
+            0 => self.remove_player(player),
+            secs => {
+                info!("player {} disconnected, waiting for them to rejoin", player);
+                self.away.insert(player, now + Duration::from_secs(secs));
+                Vec::new()
+            }
+        }
+    }
+
+    fn remove_gone_players(&mut self, now: Instant) -> Vec<Response> {
+        let gone: Vec<_> = self
+            .away
+            .iter()
+            .filter(|(_, deadline)| now >= **deadline)
+            .map(|(player, _)| *player)
+            .collect();
+        let mut resps = Vec::new();
+        for player in gone {
+            info!("player {} did not rejoin in time", player);
+            resps.extend(self.rejoin_expired(player));
+        }
+        resps
+    }
+
+    fn rejoin_expired(&mut self, player: PlayerId) -> Vec<Response> {
+        let resps = self.remove_player(player);
+        self.record(RoomEvent::RejoinExpired { player });
+        resps
+    }
+
+    fn kick(&mut self, host: PlayerId, player: PlayerId, ban: bool) -> Vec<Response> {
+        if player == host {
+            return err(host, ErrResponse::CannotKickHost);
+        }
+        if !self.rd.has_player(player) {
+            return err(host, ErrResponse::NoSuchPlayer);
+        }
+        if ban {
+            self.rd.banned.push(player);
+        }
+        self.rd.kicked.push(player);
+        self.evicted.push(Eviction {
+            player,
+            banned: ban,
+        });
+        let kicked = Response::PlayerKicked {
+            id: player,
+            banned: ban,
+        };
+        self.take_out(player, kicked)
+    }
+
+    fn remove_player(&mut self, player: PlayerId) -> Vec<Response> {
+        self.take_out(player, Response::PlayerDisconnected { id: player })
+    }
+
+    /// Removes the player from the room letting everybody know with `announcement`.
+    fn take_out(&mut self, player: PlayerId, announcement: Response) -> Vec<Response> {
+        self.away.remove(&player);
+        let before = self.rd.players.len();
+        self.rd.players.retain(|p| p.id != player);
+        self.rd.slots_freed += before - self.rd.players.len();
+        self.changed = true;
+        let mut resps = vec![announcement];
+        match self.rd.state {
+            RoomState::AcceptingPlayers | RoomState::Dead => (),
+            RoomState::Finished if self.rd.players.is_empty() => resps.extend(self.close_room()),
+            RoomState::Finished => (),
+            _ if self.rd.players.is_empty() => resps.extend(self.finish_game()),
+            RoomState::AcceptingQuestions => (),
+            RoomState::Playing => {
+                // the player we were waiting for might have just left
+                resps.extend(self.try_start_polling());
+                resps.extend(self.try_finish_round());
+            }
+        }
+        resps
+    }
+
+    fn add_question(&mut self, player: PlayerId, content: String) -> Vec<Response> {
+        if self.rd.questions.len() >= self.rd.rounds_limit {
+            return err(player, ErrResponse::QuestionLimitReached);
+        }
+        let content = match self.content.question(&content) {
+            Ok(content) => content,
+            Err(e) => return err(player, e),
+        };
+        let questions = self.rd.questions.iter().map(|q| q.content.as_str());
+        if content::is_duplicate(&content, questions) {
+            return err(player, ErrResponse::DuplicateContent);
+        }
+        let id = self.rd.questions.len();
+        self.rd.questions.push(Question {
+            id,
+            player_id: Some(player),
+            content,
+        });
+        self.changed = true;
+        let mut resps = vec![Response::QuestionAdded { id, author: player }];
+        if self.rd.questions.len() == self.rd.rounds_limit {
+            info!("all questions gathered");
+            resps.extend(self.start_game());
+        }
+        resps
+    }
+
+    fn reject_question(&mut self, host: PlayerId, question: QuestionId) -> Vec<Response> {
+        if question >= self.rd.questions.len() {
+            return err(host, ErrResponse::NoSuchQuestion);
+        }
+        self.rd.questions.remove(question);
+        // question ids are their positions
+        for (id, question) in self.rd.questions.iter_mut().enumerate() {
+            question.id = id;
+        }
+        self.changed = true;
+        vec![Response::QuestionRejected { id: question }]
+    }
+
+    /// Whether the room can be played with the given limits, the players
+    /// already in it still fit and there is room for a player in every team.
+    fn limits_allowed(&self, players_limit: usize, rounds_limit: usize) -> bool {
+        players_limit > 0
+            && players_limit >= self.rd.players.len()
+            && players_limit >= self.rd.teams
+            && rounds_limit > 0
+    }
+
+    fn change_limits(
+        &mut self,
+        host: PlayerId,
+        players_limit: Option<usize>,
+        rounds_limit: Option<usize>,
+    ) -> Vec<Response> {
+        let players_limit = players_limit.unwrap_or(self.rd.players_limit);
+        let rounds_limit = rounds_limit.unwrap_or(self.rd.rounds_limit);
+        if !self.limits_allowed(players_limit, rounds_limit)
+            || rounds_limit < self.rd.questions.len()
+        {
+            return err(host, ErrResponse::InvalidLimits);
+        }
+        self.rd.players_limit = players_limit;
+        self.rd.rounds_limit = rounds_limit;
+        self.changed = true;
+        let mut resps = vec![Response::LimitsChanged {
+            players_limit,
+            rounds_limit,
+        }];
+        if self.rd.players.len() == players_limit {
+            resps.extend(self.close_joining());
+        }
+        resps
+    }
+
+    fn start_game(&mut self) -> Vec<Response> {
+        info!("starting the game");
+        self.rd.state = RoomState::Playing;
+        self.changed = true;
+        // there might be less questions than rounds if we ran out of time
+        self.next_round()
+    }
+
+    fn add_answer(&mut self, player: PlayerId, content: String) -> Vec<Response> {
+        let round = match &self.rd.curr_round {
+            Some(round) if round.state == RoundState::AcceptingAnswers => round,
+            _ => return err(player, ErrResponse::UnexpectedRequest),
+        };
+        if self.rd.sits_out(player) {
+            return err(player, ErrResponse::UnexpectedRequest);
+        }
+        if self.rd.has_answered(round, player) {
+            return err(player, ErrResponse::AnswerAlreadySent);
+        }
+        let content = match self.content.answer(&content) {
+            Ok(content) => content,
+            Err(e) => return err(player, e),
+        };
+        // the player learns that this answer was already given,
+        // see `ErrResponse::DuplicateContent` for why that is fine
+        let answers = round.answers.values().map(|a| a.content.as_str());
+        if content::is_duplicate(&content, answers) {
+            return err(player, ErrResponse::DuplicateContent);
+        }
+        let team = self.rd.team_of(player);
+        let round = self.rd.curr_round.as_mut().unwrap(); // checked above
+        let id = round.answers.len();
+        round.answers.insert(
+            player,
+            Answer {
+                id,
+                player_id: player,
+                content,
+                team,
+                received: id,
+            },
+        );
+        self.changed = true;
+        let mut resps = vec![Response::AnswerAdded { author: player }];
+        resps.extend(self.try_start_polling());
+        resps
+    }
+
+    fn select_answer(&mut self, player: PlayerId, answer: AnswerId) -> Vec<Response> {
+        let team = self.rd.team_of(player);
+        let round = match self.rd.curr_round.as_mut() {
+            Some(round) if round.state == RoundState::Polling => round,
+            _ => return err(player, ErrResponse::UnexpectedRequest),
+        };
+        if round.polls.contains_key(&player) {
+            return err(player, ErrResponse::AnswerAlreadySelected);
+        }
+        match round.answers.values().find(|a| a.id == answer) {
+            None => return err(player, ErrResponse::NoSuchAnswer),
+            Some(a) if a.player_id == player => return err(player, ErrResponse::OwnAnswer),
+            Some(a) if team.is_some() && a.team == team => {
+                return err(player, ErrResponse::OwnTeam)
+            }
+            Some(_) => (),
+        }
+        round.polls.insert(player, answer);
+        self.changed = true;
+        let mut resps = vec![Response::AnswerSelected { voter: player }];
+        resps.extend(self.try_finish_round());
+        resps
+    }
+
+    fn try_start_polling(&mut self) -> Vec<Response> {
+        let all_answered = match &self.rd.curr_round {
+            Some(round) => {
+                round.state == RoundState::AcceptingAnswers
+                    && self
+                        .rd
+                        .players
+                        .iter()
+                        .all(|p| self.rd.has_answered(round, p.id))
+            }
+            None => false,
+        };
+        if !all_answered {
+            return Vec::new();
+        }
+        info!("all answers gathered");
+        self.start_polling()
+    }
+
+    fn start_polling(&mut self) -> Vec<Response> {
+        match &self.rd.curr_round {
+            Some(round) if round.answers.is_empty() => {
+                info!("nothing to vote for, skipping polling");
+                return self.finish_round();
+            }
+            Some(_) => (),
+            None => return Vec::new(),
+        }
+        info!("polling");
+        // ids given out so far tell in which order the answers came,
+        // random ones do not tell anything about their authors
+        let mut rng = self.rd.rng();
+        let round = self.rd.curr_round.as_mut().unwrap(); // checked above
+        round.state = RoundState::Polling;
+        // answers are gone through in a fixed order so the same seed gives
+        // every player the same id no matter how the map is laid out
+        let mut answers: Vec<_> = round.answers.values_mut().collect();
+        answers.sort_by_key(|answer| answer.player_id);
+        let mut ids = HashSet::new();
+        for answer in answers {
+            let mut id = rng.gen_range(0, ANSWER_ID_RANGE);
+            while !ids.insert(id) {
+                id = rng.gen_range(0, ANSWER_ID_RANGE);
+            }
+            answer.id = id;
+        }
+        self.changed = true;
+        let mut resps = vec![Response::PollingStarted {
+            answers: AnswerInfo::of_round(round),
+        }];
+        // somebody might have nothing to vote for but their own answer
+        resps.extend(self.try_finish_round());
+        resps
+    }
+
+    fn try_finish_round(&mut self) -> Vec<Response> {
+        let finished = match &self.rd.curr_round {
+            Some(round) => {
+                round.state == RoundState::Polling
+                    && self
+                        .rd
+                        .players
+                        .iter()
+                        .all(|p| done_voting(round, p.id, p.team))
+            }
+            None => false,
+        };
+        if !finished {
+            return Vec::new();
+        }
+        self.finish_round()
+    }
+
+    fn finish_round(&mut self) -> Vec<Response> {
+        let round = match self.rd.curr_round.take() {
+            Some(round) => round,
+            None => return Vec::new(),
+        };
+        self.changed = true;
+        info!("round {} finished", round.round_num);
+        let breakdown = self.score_round(&round);
+        let scores = self.score_table(&round, breakdown);
+        self.rd.past_rounds.push(round);
+        if self.game_over() {
+            return self.settle(scores);
+        }
+        let mut resps = vec![Response::GameScore(scores)];
+        resps.extend(self.next_round());
+        resps
+    }
+
+    /// Finishes the current round without scoring it.
+    fn skip_round(&mut self) -> Vec<Response> {
+        let round = match self.rd.curr_round.take() {
+            Some(round) => round,
+            None => return Vec::new(),
+        };
+        info!("skipping round {}", round.round_num);
+        self.changed = true;
+        let mut resps = vec![Response::RoundSkipped {
+            round_num: round.round_num,
+        }];
+        // the standings still have to be announced after the last round
+        let scores = self.score_table(&round, Breakdown::new());
+        self.rd.past_rounds.push(round);
+        if self.game_over() {
+            resps.extend(self.settle(scores));
+        } else {
+            resps.extend(self.next_round());
+        }
+        resps
+    }
+
+    fn score_round(&mut self, round: &Round) -> Breakdown {
+        let players: Vec<_> = self.rd.players.iter().map(|p| p.id).collect();
+        let answered: Vec<_> = players
+            .iter()
+            .copied()
+            .filter(|id| self.rd.has_answered(round, *id))
+            .collect();
+        let teams: HashMap<_, _> = self
+            .rd
+            .players
+            .iter()
+            .filter_map(|p| Some((p.id, p.team?)))
+            .collect();
+        let ctx = RoundContext {
+            players: &players,
+            answered: &answered,
+            teams: &teams,
+            last_round: self.rd.sudden_death.is_empty()
+                && round.round_num + 1 >= self.regular_rounds(),
+        };
+        let breakdown = scoring::score_round(&self.scoring, round, &ctx);
+        for (id, entries) in &breakdown {
+            if let Some(player) = self.rd.player_mut(*id) {
+                player.points += entries.iter().map(|e| e.points).sum::<i64>();
+            }
+        }
+        breakdown
+    }
+
+    fn score_table(&self, round: &Round, mut breakdown: Breakdown) -> ScoreTable {
+        let mut scores: Vec<_> = self
+            .rd
+            .players
+            .iter()
+            .map(|p| {
+                let breakdown = breakdown.remove(&p.id).unwrap_or_default();
+                PlayerScore {
+                    id: p.id,
+                    name: p.name.clone(),
+                    points: p.points,
+                    round_points: breakdown.iter().map(|e| e.points).sum(),
+                    breakdown,
+                    team: p.team,
+                }
+            })
+            .collect();
+        scores.sort_by(|a, b| b.points.cmp(&a.points).then(a.id.cmp(&b.id)));
+        ScoreTable {
+            round_num: round.round_num,
+            teams: TeamScore::of_players(&scores),
+            scores,
+            answers: AnswerResult::of_round(round),
+            winners: Vec::new(),
+        }
+    }
+
+    /// Rounds played before any tie is broken,
+    /// there might be less questions than rounds.
+    fn regular_rounds(&self) -> usize {
+        self.rd.rounds_limit.min(self.rd.questions.len())
+    }
+
+    /// Whether the round that just finished was the last one,
+    /// that is the last regular round or any sudden death round.
+    fn game_over(&self) -> bool {
+        !self.rd.sudden_death.is_empty() || self.rd.past_rounds.len() >= self.regular_rounds()
+    }
+
+    /// Players with the most points, or whole teams in team mode.
+    /// In sudden death only the tied players are taken into account.
+    fn leaders(&self) -> Vec<PlayerId> {
+        let mut contenders: Vec<_> = self
+            .rd
+            .players
+            .iter()
+            .filter(|p| self.rd.sudden_death.contains(&p.id))
+            .collect();
+        // the tied players might have left in the meantime
+        if contenders.is_empty() {
+            contenders = self.rd.players.iter().collect();
+        }
+        let points = |player: &Player| match player.team {
+            Some(team) => self
+                .rd
+                .players
+                .iter()
+                .filter(|p| p.team == Some(team))
+                .map(|p| p.points)
+                .sum(),
+            None => player.points,
+        };
+        let best = match contenders.iter().map(|p| points(p)).max() {
+            Some(best) => best,
+            None => return Vec::new(),
+        };
+        let mut leaders: Vec<_> = contenders
+            .into_iter()
+            .filter(|p| points(p) == best)
+            .map(|p| p.id)
+            .collect();
+        leaders.sort_unstable();
+        leaders
+    }
+
+    /// How many teams, or players without one, are among `players`.
+    fn sides(&self, players: &[PlayerId]) -> usize {
+        let mut sides: Vec<_> = players
+            .iter()
+            .map(|id| self.rd.team_of(*id).ok_or(*id))
+            .collect();
+        sides.sort_unstable();
+        sides.dedup();
+        sides.len()
+    }
+
+    /// Out of the tied `leaders` picks the side whose answer
+    /// came first in the last round. If none of them answered
+    /// there is nothing to tell them apart by.
+    fn earliest_answer(&self, leaders: Vec<PlayerId>) -> Vec<PlayerId> {
+        let first = self.rd.past_rounds.last().and_then(|round| {
+            round
+                .answers
+                .values()
+                .filter(|a| leaders.contains(&a.player_id))
+                .min_by_key(|a| a.received)
+        });
+        let first = match first {
+            Some(answer) => answer.player_id,
+            None => return leaders,
+        };
+        let side = self.rd.team_of(first).ok_or(first);
+        leaders
+            .into_iter()
+            .filter(|id| self.rd.team_of(*id).ok_or(*id) == side)
+            .collect()
+    }
+
+    /// Takes a question nobody has been asked yet for a sudden death round.
+    fn spare_question(&mut self) -> Option<Question> {
+        while !self.rd.spare_questions.is_empty() {
+            let question = self.rd.spare_questions.remove(0);
+            let asked = self.rd.questions.iter().map(|q| q.content.as_str());
+            // players might have come up with the same question
+            if !content::is_duplicate(&question.content, asked) {
+                return Some(question);
+            }
+        }
+        None
+    }
+
+    /// Announces the standings after the last round and either
+    /// names the winners or goes into sudden death if there is a tie.
+    /// Without questions left to ask the tied players share the victory.
+    fn settle(&mut self, mut scores: ScoreTable) -> Vec<Response> {
+        let leaders = self.leaders();
+        let tied = self.sides(&leaders) > 1;
+        let extra_rounds = self
+            .rd
+            .past_rounds
+            .len()
+            .saturating_sub(self.regular_rounds());
+        let question = if tied
+            && self.rd.tie_break == TieBreak::SuddenDeath
+            && extra_rounds < MAX_SUDDEN_DEATH_ROUNDS
+        {
+            self.spare_question()
+        } else {
+            None
+        };
+        if let Some(question) = question {
+            info!("sudden death between {:?}", leaders);
+            self.rd.sudden_death = leaders.clone();
+            self.changed = true;
+            let round_num = self.rd.past_rounds.len();
+            let mut resps = vec![
+                Response::GameScore(scores),
+                Response::SuddenDeath { players: leaders },
+            ];
+            resps.extend(self.start_round(round_num, question));
+            return resps;
+        }
+        scores.winners = match self.rd.tie_break {
+            TieBreak::EarliestAnswer if tied => self.earliest_answer(leaders),
+            _ => leaders,
+        };
+        info!("game won by {:?}", scores.winners);
+        let mut resps = vec![Response::GameScore(scores)];
+        resps.extend(self.finish_game());
+        resps
+    }
+
+    fn next_round(&mut self) -> Vec<Response> {
+        let round_num = self.rd.past_rounds.len();
+        if round_num >= self.rd.rounds_limit {
+            return self.finish_game();
+        }
+        let question = match self.rd.questions.get(round_num) {
+            Some(question) => question.clone(),
+            None => return self.finish_game(),
+        };
+        self.start_round(round_num, question)
+    }
+
+    fn start_round(&mut self, round_num: usize, question: Question) -> Vec<Response> {
+        info!("starting round {}", round_num);
+        let content = question.content.clone();
+        self.rd.curr_round = Some(Round {
+            round_num,
+            state: RoundState::AcceptingAnswers,
+            question,
+            answers: Default::default(),
+            polls: Default::default(),
+            audience_polls: Default::default(),
+        });
+        vec![Response::NewRound {
+            round_num,
+            question: content,
+        }]
+    }
+
+    fn finish_game(&mut self) -> Vec<Response> {
+        info!("game finished");
+        self.rd.sudden_death.clear();
+        // there is nobody left to play a rematch with
+        // or nobody who could start it
+        self.rd.state = if self.timers.rematch_secs == 0
+            || self.rd.players.is_empty()
+            || self.rd.host_token.is_none()
+        {
+            RoomState::Dead
+        } else {
+            RoomState::Finished
+        };
+        self.changed = true;
+        vec![Response::GameFinished]
+    }
+
+    /// Starts the game over with the players still in the room,
+    /// the finished game is kept in the room's history.
+    fn rematch(
+        &mut self,
+        host: PlayerId,
+        players_limit: Option<usize>,
+        rounds_limit: Option<usize>,
+    ) -> Vec<Response> {
+        let players_limit = players_limit.unwrap_or(self.rd.players_limit);
+        let rounds_limit = rounds_limit.unwrap_or(self.rd.rounds_limit);
+        if !self.limits_allowed(players_limit, rounds_limit) {
+            return err(host, ErrResponse::InvalidLimits);
+        }
+        info!("starting a rematch");
+        let mut rounds = std::mem::take(&mut self.rd.past_rounds);
+        // the game might have been ended in the middle of a round
+        rounds.extend(self.rd.curr_round.take());
+        self.rd.past_games.push(GameRecord {
+            rounds,
+            players: self.rd.players.clone(),
+        });
+        for player in self.rd.players.iter_mut() {
+            player.points = 0;
+        }
+        self.rd.questions.clear();
+        let count = self
+            .rd
+            .pack_questions
+            .unwrap_or(rounds_limit)
+            .min(rounds_limit);
+        while self.rd.questions.len() < count {
+            match self.spare_question() {
+                Some(mut question) => {
+                    question.id = self.rd.questions.len();
+                    self.rd.questions.push(question);
+                }
+                None => break,
+            }
+        }
+        self.rd.players_limit = players_limit;
+        self.rd.rounds_limit = rounds_limit;
+        self.rd.state = RoomState::AcceptingPlayers;
+        self.changed = true;
+        let mut resps = vec![Response::RematchStarted {
+            players_limit,
+            rounds_limit,
+        }];
+        // players who left freed their slots for somebody else
+        if self.rd.players.len() == players_limit {
+            resps.extend(self.close_joining());
+        }
+        resps
+    }
+
+    fn close_room(&mut self) -> Vec<Response> {
+        info!("closing the room");
+        self.rd.state = RoomState::Dead;
+        self.changed = true;
+        vec![Response::RoomClosed]
+    }
+}
+
+/// Rebuilds the room out of its logged events. Timers are what made
+/// phases expire when the game was played, which is logged on its own,
+/// so they are turned off and only their policies are kept.
+/// Returns `None` if the log does not start with the room being started.
+pub fn replay<I: IntoIterator<Item = RoomEvent>>(events: I) -> Option<Room> {
+    let mut rt: Option<Runtime> = None;
+    for event in events {
+        match event {
+            // resumed rooms start over from the state they were saved with
+            RoomEvent::Started {
+                room,
+                timers,
+                content,
+            } => {
+                let timers = Timers {
+                    questions_secs: 0,
+                    answers_secs: 0,
+                    polling_secs: 0,
+                    tick_secs: 0,
+                    ..timers
+                };
+                let mut resumed = Runtime::with_settings(*room, timers, content);
+                resumed.replaying = true;
+                rt = Some(resumed);
+            }
+            RoomEvent::Request { sender, request } => {
+                rt.as_mut()?.process_msg(&sender, request);
+            }
+            RoomEvent::PhaseExpired { phase } => {
+                rt.as_mut()?.expire(phase);
+            }
+            RoomEvent::RejoinExpired { player } => {
+                rt.as_mut()?.rejoin_expired(player);
+            }
+        }
+    }
+    rt.map(|rt| rt.rd)
+}
+
+/// Puts the credentials left out of the log back into the replayed room.
+fn restore(room: &mut Room, credentials: &Credentials) {
+    room.pass = credentials.password;
+    room.host_token = room.host_token.map(|_| credentials.host_token);
+    room.token_key = credentials.token_key;
+    for player in room.players.iter_mut() {
+        player.token = model::player_token(room.token_key, player.id);
+    }
+}
+
+impl GameMode for Runtime {
+    type State = Room;
+    type Request = Request;
+    type Response = Response;
+    type Event = RoomEvent;
+
+    const KIND: GameKind = GameKind::QuestionsAndAnswers;
+
+    fn setup(
+        id: RoomId,
+        credentials: &Credentials,
+        seed: u64,
+        req: &NewRoomReq,
+        packs: &PackLibrary,
+    ) -> Room {
+        let mut room = Room::new(
+            id,
+            credentials.password,
+            req.players_limit,
+            req.rounds_limit,
+        );
+        room.scoring = req.scoring.clone();
+        room.host_token = Some(credentials.host_token);
+        room.token_key = credentials.token_key;
+        room.seed = seed;
+        room.teams = req.teams;
+        room.team_answers = req.team_answers;
+        room.tie_break = req.tie_break;
+        room.pack_questions = req.pack_questions;
+        let count = req
+            .pack_questions
+            .unwrap_or(req.rounds_limit)
+            .min(req.rounds_limit);
+        // the whole packs are drawn up front, questions not asked in this game
+        // are kept for sudden death and rematches so they never repeat
+        room.questions = packs
+            .draw(&req.packs, usize::MAX, &mut room.rng())
+            .into_iter()
+            .enumerate()
+            .map(|(id, content)| Question {
+                id,
+                player_id: None,
+                content,
+            })
+            .collect();
+        room.spare_questions = room.questions.split_off(count.min(room.questions.len()));
+        room
+    }
+
+    fn from_state(state: Room, config: Config) -> Self {
+        Self::new(state, config)
+    }
+
+    fn replay(events: Vec<RoomEvent>, credentials: &Credentials) -> Option<Room> {
+        let mut room = replay(events)?;
+        restore(&mut room, credentials);
+        Some(room)
+    }
+
+    fn state(&self) -> &Room {
+        &self.rd
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            state: self.rd.state,
+            players: self.rd.players.len(),
+            players_limit: self.rd.players_limit,
+            slots_freed: self.rd.slots_freed,
+            rounds_limit: self.rd.rounds_limit,
+            round: self.rd.curr_round.as_ref().map(|round| round.round_num),
+        }
+    }
+
+    fn process_msg(&mut self, player: &str, msg: Request) -> service::Command<Response> {
+        let changed = std::mem::replace(&mut self.changed, false);
+        let request = msg.clone();
+        let mut resps = match player {
+            "rt" => {
+                info!("global msg {:?}", msg);
+                self.process_global_msg(msg)
+            }
+            player => match player.parse::<PlayerId>() {
+                Ok(id) => {
+                    info!("msg from player {}: {:?}", id, msg);
+                    self.process_player_msg(id, msg)
+                }
+                Err(_) => match service::spectator_from_user(player) {
+                    Some(id) => {
+                        info!("msg from spectator {}: {:?}", id, msg);
+                        self.process_spectator_msg(id, msg)
+                    }
+                    None => {
+                        warn!("msg from unknown sender {}: {:?}", player, msg);
+                        Vec::new()
+                    }
+                },
+            },
+        };
+        if self.changed {
+            self.record(RoomEvent::Request {
+                sender: player.into(),
+                request,
+            });
+        }
+        self.changed |= changed;
+        resps.extend(self.sync_timer(Instant::now()));
+        into_command(resps)
+    }
+
+    /// Moves the game forward if the current phase ran out of time,
+    /// otherwise lets the players know how much time they have left.
+    fn process_timer(&mut self, now: Instant) -> service::Command<Response> {
+        let mut resps = self.remove_gone_players(now);
+        resps.extend(self.sync_timer(now));
+        let (phase, deadline) = match &self.timer {
+            Some(timer) => (timer.phase, timer.deadline),
+            None => return into_command(resps),
+        };
+        if now >= deadline {
+            self.timer = None;
+            resps.extend(self.expire(phase));
+            resps.extend(self.sync_timer(now));
+        } else if let Some(timer) = self.timer.as_mut() {
+            match timer.next_tick {
+                Some(tick) if now >= tick => {
+                    timer.next_tick = Some(now + Duration::from_secs(self.timers.tick_secs));
+                    let secs = secs_left(deadline, now);
+                    resps.push(Response::TimeLeft { phase, secs });
+                }
+                _ => (),
+            }
+        }
+        into_command(resps)
+    }
+
+    fn wakeup(&self) -> Option<Instant> {
+        let timer = self.timer.as_ref().map(|timer| match timer.next_tick {
+            Some(tick) => tick.min(timer.deadline),
+            None => timer.deadline,
+        });
+        let away = self.away.values().min().copied();
+        match (timer, away) {
+            (Some(timer), Some(away)) => Some(timer.min(away)),
+            (timer, away) => timer.or(away),
+        }
+    }
+
+    fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    fn take_log(&mut self) -> Vec<LogEntry<RoomEvent>> {
+        std::mem::take(&mut self.log)
+    }
+
+    fn take_evicted(&mut self) -> Vec<Eviction> {
+        std::mem::take(&mut self.evicted)
+    }
+}
+
+impl ModeRequest for Request {
+    fn connection_lost() -> Self {
+        Request::Disconnecting
+    }
+}
+
+impl ModeResponse for Response {
+    fn runtime_started() -> Self {
+        Response::RuntimeStarted
+    }
+
+    fn server_shutting_down() -> Self {
+        Response::ServerShuttingDown
+    }
+
+    fn runtime_lost() -> Self {
+        Response::RuntimeLost
+    }
+
+    fn route(self) -> (Option<PlayerId>, Self) {
+        match self {
+            Response::Priv(player, resp) => (Some(player), *resp),
+            resp => (None, resp),
+        }
+    }
+}
+
+fn priv_resp(player: PlayerId, resp: Response) -> Vec<Response> {
+    vec![Response::Priv(player, Box::new(resp))]
+}
+
+fn err(player: PlayerId, err: ErrResponse) -> Vec<Response> {
+    priv_resp(player, Response::Err(err))
+}
+
+/// Players cannot vote for their own or their team's answers
+/// so if there is nothing else to vote for they are not waited for.
+fn done_voting(round: &Round, player: PlayerId, team: Option<TeamId>) -> bool {
+    round.polls.contains_key(&player)
+        || round
+            .answers
+            .values()
+            .all(|a| a.player_id == player || (team.is_some() && a.team == team))
+}
+
+fn secs_left(deadline: Instant, now: Instant) -> u64 {
+    let left = deadline - now;
+    // rounding up so nobody sees 0 while there is still time
+    left.as_secs() + u64::from(left.subsec_nanos() > 0)
+}
+
+fn into_command(mut resps: Vec<Response>) -> service::Command<Response> {
+    match resps.len() {
+        0 => service::Command::Skip,
+        1 => service::Command::Response(resps.pop().unwrap()),
+        _ => service::Command::Responses(resps),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn runtime(players_limit: usize, rounds_limit: usize) -> Runtime {
+        Runtime::new(
+            Room::new([0; 12], 0, players_limit, rounds_limit),
+            test_config(),
+        )
+    }
+
+    fn timed_runtime(players_limit: usize, rounds_limit: usize, policy: IdlePolicy) -> Runtime {
+        let mut config = test_config();
+        config.timers = crate::config::Timers {
+            questions_secs: 60,
+            answers_secs: 30,
+            polling_secs: 20,
+            tick_secs: 0,
+            idle_policy: policy,
+            rejoin_grace_secs: 15,
+            rematch_secs: 0,
+        };
+        Runtime::new(Room::new([0; 12], 0, players_limit, rounds_limit), config)
+    }
+
+    fn resps(cmd: service::Command<Response>) -> Vec<Response> {
+        match cmd {
+            service::Command::Skip => Vec::new(),
+            service::Command::Response(resp) => vec![resp],
+            service::Command::Responses(resps) => resps,
+            service::Command::Abort(_) => panic!("runtime should not abort"),
         }
     }
 
-    pub(crate) async fn process_msg(
-        &self,
-        player: &str,
-        msg: message::Request,
-    ) -> service::Command {
-        match player {
-            "rt" => info!("global msg {:?}", msg),
-            player => info!("msg from player {}: {:?}", player, msg),
+    fn send(rt: &mut Runtime, player: &str, msg: Request) -> Vec<Response> {
+        resps(rt.process_msg(player, msg))
+    }
+
+    fn resps_after(rt: &mut Runtime, now: Instant) -> Vec<Response> {
+        resps(rt.process_timer(now))
+    }
+
+    /// Pretends the current phase ran out of time.
+    fn time_out(rt: &mut Runtime) -> Vec<Response> {
+        let deadline = rt.timer.as_ref().expect("no timer running").deadline;
+        resps(rt.process_timer(deadline))
+    }
+
+    fn join(rt: &mut Runtime, player: &str) -> Vec<Response> {
+        send(
+            rt,
+            player,
+            Request::JoinRoom {
+                name: format!("player {}", player),
+                token: model::player_token(rt.room().token_key, player.parse().unwrap()),
+            },
+        )
+    }
+
+    fn question(rt: &mut Runtime, player: &str) -> Vec<Response> {
+        // the same question cannot be asked twice
+        let content = format!("why {} number {}?", player, rt.room().questions.len());
+        send(rt, player, Request::AddQuestion { content })
+    }
+
+    fn answer(rt: &mut Runtime, player: &str) -> Vec<Response> {
+        send(
+            rt,
+            player,
+            Request::AddAnswer {
+                content: format!("because {}", player),
+            },
+        )
+    }
+
+    fn vote(rt: &mut Runtime, player: &str, answer: AnswerId) -> Vec<Response> {
+        send(rt, player, Request::SelectAnswer { answer })
+    }
+
+    fn answer_id_of(rt: &Runtime, player: PlayerId) -> AnswerId {
+        rt.room().curr_round.as_ref().unwrap().answers[&player].id
+    }
+
+    fn assert_err(resps: &[Response], expected: ErrResponse) {
+        match resps {
+            [Response::Priv(_, resp)] => match resp.as_ref() {
+                Response::Err(e) => {
+                    assert_eq!(std::mem::discriminant(e), std::mem::discriminant(&expected))
+                }
+                other => panic!("expected error, got {:?}", other),
+            },
+            other => panic!("expected a single private response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn room_accepts_questions_once_full() {
+        let mut rt = runtime(2, 1);
+        let resps = join(&mut rt, "0");
+        assert!(matches!(resps.as_slice(), [Response::NewPlayerJoined(..)]));
+        assert_eq!(rt.room().state, RoomState::AcceptingPlayers);
+        join(&mut rt, "1");
+        assert_eq!(rt.room().state, RoomState::AcceptingQuestions);
+        assert_eq!(rt.room().players.len(), 2);
+    }
+
+    #[test]
+    fn player_cannot_join_twice() {
+        let mut rt = runtime(2, 1);
+        join(&mut rt, "0");
+        assert_err(&join(&mut rt, "0"), ErrResponse::AlreadyJoined);
+    }
+
+    #[test]
+    fn players_need_the_token_they_were_given() {
+        let mut rt = runtime(2, 1);
+        rt.rd.token_key = 42;
+        let join = |token| Request::JoinRoom {
+            name: "Alice".into(),
+            token,
+        };
+        // tokens of other players or other rooms do not work
+        for token in &[model::player_token(42, 1), model::player_token(0, 0)] {
+            assert_err(&send(&mut rt, "0", join(*token)), ErrResponse::InvalidToken);
+        }
+        let resps = send(&mut rt, "0", join(model::player_token(42, 0)));
+        assert!(matches!(resps.as_slice(), [Response::NewPlayerJoined(..)]));
+    }
+
+    #[test]
+    fn requests_from_unknown_players_are_rejected() {
+        let mut rt = runtime(2, 1);
+        assert_err(&question(&mut rt, "0"), ErrResponse::NotJoined);
+        assert!(send(&mut rt, "nobody", Request::GetRoomState).is_empty());
+    }
+
+    #[test]
+    fn questions_are_limited_by_rounds() {
+        let mut rt = runtime(1, 2);
+        join(&mut rt, "0");
+        question(&mut rt, "0");
+        let resps = question(&mut rt, "0");
+        assert!(matches!(
+            resps.as_slice(),
+            [Response::QuestionAdded { .. }, Response::NewRound { .. }]
+        ));
+        assert_eq!(rt.room().state, RoomState::Playing);
+        assert_err(&question(&mut rt, "0"), ErrResponse::UnexpectedRequest);
+    }
+
+    #[test]
+    fn full_game() {
+        let mut rt = runtime(2, 2);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        question(&mut rt, "1");
+        for round in 0..2 {
+            assert_eq!(rt.room().curr_round.as_ref().unwrap().round_num, round);
+            answer(&mut rt, "0");
+            let resps = answer(&mut rt, "1");
+            assert!(matches!(
+                resps.as_slice(),
+                [
+                    Response::AnswerAdded { .. },
+                    Response::PollingStarted { .. }
+                ]
+            ));
+            let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 1));
+            vote(&mut rt, "0", of_second);
+            let resps = vote(&mut rt, "1", of_first);
+            if round == 0 {
+                assert!(matches!(
+                    resps.as_slice(),
+                    [
+                        Response::AnswerSelected { .. },
+                        Response::GameScore(..),
+                        Response::NewRound { .. }
+                    ]
+                ));
+            } else {
+                assert!(matches!(
+                    resps.as_slice(),
+                    [
+                        Response::AnswerSelected { .. },
+                        Response::GameScore(..),
+                        Response::GameFinished
+                    ]
+                ));
+            }
+        }
+        assert!(rt.is_finished());
+        assert_eq!(rt.room().past_rounds.len(), 2);
+        assert_eq!(rt.room().player(0).unwrap().points, 2);
+        assert_eq!(rt.room().player(1).unwrap().points, 2);
+    }
+
+    #[test]
+    fn answers_and_votes_are_checked() {
+        let mut rt = runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        assert_err(&vote(&mut rt, "0", 0), ErrResponse::UnexpectedRequest);
+        answer(&mut rt, "0");
+        assert_err(&answer(&mut rt, "0"), ErrResponse::AnswerAlreadySent);
+        answer(&mut rt, "1");
+        assert_err(&answer(&mut rt, "1"), ErrResponse::UnexpectedRequest);
+        assert_err(
+            &vote(&mut rt, "0", ANSWER_ID_RANGE),
+            ErrResponse::NoSuchAnswer,
+        );
+        let own = answer_id_of(&rt, 0);
+        assert_err(&vote(&mut rt, "0", own), ErrResponse::OwnAnswer);
+        let of_second = answer_id_of(&rt, 1);
+        vote(&mut rt, "0", of_second);
+        assert_err(&vote(&mut rt, "0", own), ErrResponse::AnswerAlreadySelected);
+    }
+
+    #[test]
+    fn leaving_player_does_not_stall_the_round() {
+        let mut rt = runtime(3, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        answer(&mut rt, "1");
+        let resps = send(&mut rt, "2", Request::Disconnecting);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::PlayerDisconnected { .. },
+                Response::PollingStarted { .. }
+            ]
+        ));
+        let of_second = answer_id_of(&rt, 1);
+        vote(&mut rt, "0", of_second);
+        let resps = send(&mut rt, "1", Request::Disconnecting);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::PlayerDisconnected { .. },
+                Response::GameScore(..),
+                Response::GameFinished
+            ]
+        ));
+    }
+
+    #[test]
+    fn only_accepted_requests_change_the_room() {
+        let mut rt = runtime(2, 1);
+        assert!(!rt.take_changed());
+        join(&mut rt, "0");
+        assert!(rt.take_changed());
+        assert!(!rt.take_changed());
+        join(&mut rt, "0");
+        send(&mut rt, "0", Request::GetRoomState);
+        assert!(!rt.take_changed());
+    }
+
+    #[test]
+    fn room_survives_serialization() {
+        let mut rt = runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        let snapshot = serde_json::to_string(rt.room()).unwrap();
+        let room: Room = serde_json::from_str(&snapshot).unwrap();
+        let mut rt = Runtime::new(room, test_config());
+        assert_eq!(rt.room().state, RoomState::Playing);
+        assert_err(&answer(&mut rt, "0"), ErrResponse::AnswerAlreadySent);
+        let resps = answer(&mut rt, "1");
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::AnswerAdded { .. },
+                Response::PollingStarted { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn room_dies_when_everyone_leaves() {
+        let mut rt = runtime(1, 1);
+        join(&mut rt, "0");
+        send(&mut rt, "0", Request::Disconnecting);
+        assert!(rt.is_finished());
+    }
+
+    #[test]
+    fn timers_follow_the_phases() {
+        let mut rt = timed_runtime(2, 1, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        assert!(rt.wakeup().is_none());
+        let resps = join(&mut rt, "1");
+        assert_eq!(
+            resps.last(),
+            Some(&Response::TimeLeft {
+                phase: Phase::Questions,
+                secs: 60
+            })
+        );
+        question(&mut rt, "0");
+        assert_eq!(rt.timer.as_ref().unwrap().phase, Phase::Answers);
+    }
+
+    #[test]
+    fn game_starts_with_questions_gathered_in_time() {
+        let mut rt = timed_runtime(2, 3, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        let resps = time_out(&mut rt);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::NewRound { round_num: 0, .. },
+                Response::TimeLeft {
+                    phase: Phase::Answers,
+                    ..
+                }
+            ]
+        ));
+        assert_eq!(rt.room().state, RoomState::Playing);
+    }
+
+    #[test]
+    fn idle_players_do_not_stall_the_round() {
+        let mut rt = timed_runtime(2, 1, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        let resps = time_out(&mut rt);
+        assert!(matches!(
+            resps.as_slice(),
+            [Response::PollingStarted { answers }, Response::TimeLeft { .. }] if answers.len() == 1
+        ));
+        // the only answer is the one of the first player so only the second one can vote
+        let resps = time_out(&mut rt);
+        assert!(matches!(
+            resps.as_slice(),
+            [Response::GameScore(..), Response::GameFinished]
+        ));
+        assert_eq!(rt.room().players.len(), 2);
+        assert!(rt.wakeup().is_none());
+    }
+
+    #[test]
+    fn round_without_answers_is_skipped() {
+        let mut rt = timed_runtime(2, 2, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        question(&mut rt, "1");
+        let resps = time_out(&mut rt);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::GameScore(..),
+                Response::NewRound { round_num: 1, .. },
+                Response::TimeLeft { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn idle_players_can_be_kicked() {
+        let mut rt = timed_runtime(3, 1, IdlePolicy::Kick);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        answer(&mut rt, "2");
+        let resps = time_out(&mut rt);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::PlayerDisconnected { id: 1 },
+                Response::PollingStarted { .. },
+                Response::TimeLeft { .. }
+            ]
+        ));
+        assert!(!rt.room().has_player(1));
+    }
+
+    #[test]
+    fn kicking_idle_players_does_not_skip_the_next_round() {
+        let mut rt = timed_runtime(2, 2, IdlePolicy::Kick);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        question(&mut rt, "1");
+        answer(&mut rt, "0");
+        // the only player left has nobody to vote for so the round ends
+        let resps = time_out(&mut rt);
+        assert!(resps
+            .iter()
+            .any(|resp| matches!(resp, Response::NewRound { round_num: 1, .. })));
+        let round = rt.room().curr_round.as_ref().unwrap();
+        assert_eq!(round.round_num, 1);
+        assert_eq!(round.state, RoundState::AcceptingAnswers);
+    }
+
+    #[test]
+    fn ticks_tell_how_much_time_is_left() {
+        let mut config = test_config();
+        config.timers.answers_secs = 30;
+        config.timers.tick_secs = 10;
+        let mut rt = Runtime::new(Room::new([0; 12], 0, 1, 1), config);
+        join(&mut rt, "0");
+        question(&mut rt, "0");
+        let tick = rt.wakeup().unwrap();
+        assert_eq!(
+            resps(rt.process_timer(tick)),
+            vec![Response::TimeLeft {
+                phase: Phase::Answers,
+                secs: 20
+            }]
+        );
+        assert!(rt.wakeup().unwrap() > tick);
+        assert_eq!(rt.room().state, RoomState::Playing);
+    }
+
+    fn packed_runtime(
+        players_limit: usize,
+        rounds_limit: usize,
+        from_packs: usize,
+        tie_break: TieBreak,
+    ) -> Runtime {
+        let packs = PackLibrary::new(vec![crate::packs::QuestionPack {
+            id: "pack".into(),
+            language: "en".into(),
+            tags: Vec::new(),
+            questions: vec!["why?".into(), "how?".into(), "when?".into()],
+        }])
+        .unwrap();
+        let req = NewRoomReq {
+            mode: Default::default(),
+            players_limit,
+            rounds_limit,
+            scoring: crate::room::scoring::default_rules(),
+            packs: vec!["pack".into()],
+            pack_questions: Some(from_packs),
+            teams: 0,
+            team_answers: false,
+            tie_break,
+        };
+        let credentials = Credentials {
+            password: 0,
+            host_token: HOST_TOKEN,
+            token_key: 0,
+        };
+        let mut config = test_config();
+        config.timers.rematch_secs = 60;
+        Runtime::new(
+            Runtime::setup([0; 12], &credentials, 0, &req, &packs),
+            config,
+        )
+    }
+
+    #[test]
+    fn room_with_pack_questions_starts_once_full() {
+        let mut rt = packed_runtime(2, 2, 2, TieBreak::SharedVictory);
+        assert_eq!(rt.room().questions.len(), 2);
+        assert!(rt.room().questions.iter().all(|q| q.player_id.is_none()));
+        join(&mut rt, "0");
+        let resps = join(&mut rt, "1");
+        assert!(matches!(resps.last(), Some(Response::NewRound { .. })));
+        assert_eq!(rt.room().state, RoomState::Playing);
+    }
+
+    #[test]
+    fn pack_questions_can_be_mixed_with_players_ones() {
+        let mut rt = packed_runtime(1, 3, 2, TieBreak::SharedVictory);
+        join(&mut rt, "0");
+        assert_eq!(rt.room().state, RoomState::AcceptingQuestions);
+        let resps = question(&mut rt, "0");
+        assert!(matches!(resps.last(), Some(Response::NewRound { .. })));
+        assert_eq!(rt.room().questions.len(), 3);
+    }
+
+    #[test]
+    fn sudden_death_questions_are_not_asked_in_regular_rounds() {
+        let rt = packed_runtime(2, 2, 2, TieBreak::SuddenDeath);
+        let room = rt.room();
+        assert_eq!(room.questions.len(), 2);
+        // the pack has only one question more
+        assert_eq!(room.spare_questions.len(), 1);
+        let spare = &room.spare_questions[0];
+        assert_eq!(spare.id, 2);
+        assert!(room.questions.iter().all(|q| q.content != spare.content));
+    }
+
+    #[test]
+    fn rematch_asks_pack_questions_not_asked_before() {
+        let mut rt = packed_runtime(2, 1, 1, TieBreak::SharedVictory);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let asked = rt.room().questions[0].content.clone();
+        answer(&mut rt, "0");
+        answer(&mut rt, "1");
+        let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 1));
+        vote(&mut rt, "0", of_second);
+        vote(&mut rt, "1", of_first);
+        assert_eq!(rt.room().state, RoomState::Finished);
+        let rematch = HostCommand::Rematch {
+            players_limit: None,
+            rounds_limit: None,
+        };
+        let resps = host(&mut rt, "0", rematch);
+        assert!(matches!(resps.last(), Some(Response::NewRound { .. })));
+        let room = rt.room();
+        assert_eq!(room.state, RoomState::Playing);
+        assert_eq!(room.questions.len(), 1);
+        assert_eq!(room.questions[0].id, 0);
+        assert_ne!(room.questions[0].content, asked);
+        assert_eq!(room.spare_questions.len(), 1);
+    }
+
+    #[test]
+    fn players_can_rejoin_with_their_token() {
+        let mut rt = timed_runtime(2, 1, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        answer(&mut rt, "1");
+        assert!(send(&mut rt, "1", Request::Disconnecting).is_empty());
+        assert!(rt.room().has_player(1));
+        let token = model::player_token(0, 1);
+        assert_err(
+            &send(
+                &mut rt,
+                "1",
+                Request::Rejoin {
+                    token: token.wrapping_add(1),
+                },
+            ),
+            ErrResponse::InvalidToken,
+        );
+        let resps = send(&mut rt, "1", Request::Rejoin { token });
+        match resps.as_slice() {
+            [Response::Priv(1, resp)] => match resp.as_ref() {
+                Response::RoomState(snapshot) => {
+                    assert_eq!(snapshot.round.as_ref().unwrap().answered, vec![1])
+                }
+                resp => panic!("expected room state, got {:?}", resp),
+            },
+            resps => panic!("expected private room state, got {:?}", resps),
+        }
+        // the player is not going anywhere now
+        let grace = Instant::now() + Duration::from_secs(15);
+        resps_after(&mut rt, grace);
+        assert!(rt.room().has_player(1));
+    }
+
+    #[test]
+    fn players_are_removed_if_they_do_not_rejoin() {
+        let mut rt = timed_runtime(3, 1, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        answer(&mut rt, "2");
+        send(&mut rt, "1", Request::Disconnecting);
+        let deadline = rt.away[&1];
+        assert_eq!(rt.wakeup(), Some(deadline));
+        let resps = resps_after(&mut rt, deadline);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::PlayerDisconnected { id: 1 },
+                Response::PollingStarted { .. },
+                Response::TimeLeft { .. }
+            ]
+        ));
+        assert!(!rt.room().has_player(1));
+    }
+
+    #[test]
+    fn active_players_are_not_removed() {
+        let mut rt = timed_runtime(3, 1, IdlePolicy::Ignore);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        question(&mut rt, "0");
+        send(&mut rt, "1", Request::Disconnecting);
+        let deadline = rt.away[&1];
+        // the connection came back without the player asking to rejoin
+        answer(&mut rt, "1");
+        assert!(rt.away.is_empty());
+        resps_after(&mut rt, deadline);
+        assert!(rt.room().has_player(1));
+    }
+
+    const HOST_TOKEN: PlayerToken = 7;
+
+    fn hosted_runtime(players_limit: usize, rounds_limit: usize) -> Runtime {
+        let mut room = Room::new([0; 12], 0, players_limit, rounds_limit);
+        room.host_token = Some(HOST_TOKEN);
+        Runtime::new(room, test_config())
+    }
+
+    fn host(rt: &mut Runtime, player: &str, cmd: HostCommand) -> Vec<Response> {
+        let token = HOST_TOKEN;
+        send(rt, player, Request::Host { token, cmd })
+    }
+
+    fn rematch_runtime(players_limit: usize, rounds_limit: usize) -> Runtime {
+        let mut config = test_config();
+        config.timers.rematch_secs = 60;
+        let mut room = Room::new([0; 12], 0, players_limit, rounds_limit);
+        room.host_token = Some(HOST_TOKEN);
+        Runtime::new(room, config)
+    }
+
+    /// Plays a single round game in which both players vote for each other.
+    fn play_short_game(rt: &mut Runtime) -> Vec<Response> {
+        question(rt, "0");
+        answer(rt, "0");
+        answer(rt, "1");
+        let (of_first, of_second) = (answer_id_of(rt, 0), answer_id_of(rt, 1));
+        vote(rt, "0", of_second);
+        vote(rt, "1", of_first)
+    }
+
+    #[test]
+    fn only_host_can_moderate() {
+        let mut rt = hosted_runtime(3, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let req = Request::Host {
+            token: 0,
+            cmd: HostCommand::Kick { player: 0 },
+        };
+        assert_err(&send(&mut rt, "1", req), ErrResponse::NotHost);
+        assert!(rt.room().has_player(0));
+        // rooms without a host cannot be moderated at all
+        let mut rt = runtime(2, 1);
+        join(&mut rt, "0");
+        assert_err(
+            &host(&mut rt, "0", HostCommand::EndGame),
+            ErrResponse::NotHost,
+        );
+    }
+
+    #[test]
+    fn host_can_start_without_waiting() {
+        let mut rt = hosted_runtime(3, 2);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        host(&mut rt, "0", HostCommand::StartGame);
+        assert_eq!(rt.room().state, RoomState::AcceptingQuestions);
+        assert_err(
+            &host(&mut rt, "0", HostCommand::StartGame),
+            ErrResponse::UnexpectedRequest,
+        );
+        question(&mut rt, "1");
+        let resps = host(&mut rt, "0", HostCommand::StartGame);
+        assert!(matches!(resps.as_slice(), [Response::NewRound { .. }]));
+        assert_eq!(rt.room().state, RoomState::Playing);
+    }
+
+    #[test]
+    fn banned_players_cannot_come_back() {
+        let mut rt = hosted_runtime(4, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        assert_err(
+            &host(&mut rt, "0", HostCommand::Ban { player: 0 }),
+            ErrResponse::CannotKickHost,
+        );
+        let resps = host(&mut rt, "0", HostCommand::Kick { player: 1 });
+        assert_eq!(
+            resps,
+            vec![Response::PlayerKicked {
+                id: 1,
+                banned: false
+            }]
+        );
+        assert_err(
+            &host(&mut rt, "0", HostCommand::Ban { player: 1 }),
+            ErrResponse::NoSuchPlayer,
+        );
+        host(&mut rt, "0", HostCommand::Ban { player: 2 });
+        assert_eq!(
+            rt.take_evicted(),
+            vec![
+                Eviction {
+                    player: 1,
+                    banned: false
+                },
+                Eviction {
+                    player: 2,
+                    banned: true
+                }
+            ]
+        );
+        // whatever they send over their old connections is ignored,
+        // they have to join again as new players
+        assert!(join(&mut rt, "1").is_empty());
+        assert!(join(&mut rt, "2").is_empty());
+        assert!(!rt.room().has_player(1));
+        assert!(!rt.room().has_player(2));
+        // both of them got an id that nobody else can take now
+        let summary = rt.summary();
+        assert_eq!(summary.players_limit + summary.slots_freed, 6);
+    }
+
+    #[test]
+    fn host_can_reject_questions() {
+        let mut rt = hosted_runtime(1, 2);
+        join(&mut rt, "0");
+        question(&mut rt, "0");
+        assert_err(
+            &host(&mut rt, "0", HostCommand::RejectQuestion { question: 1 }),
+            ErrResponse::NoSuchQuestion,
+        );
+        let resps = host(&mut rt, "0", HostCommand::RejectQuestion { question: 0 });
+        assert_eq!(resps, vec![Response::QuestionRejected { id: 0 }]);
+        assert!(rt.room().questions.is_empty());
+        question(&mut rt, "0");
+        question(&mut rt, "0");
+        assert_eq!(rt.room().state, RoomState::Playing);
+    }
+
+    #[test]
+    fn host_can_skip_rounds_and_end_the_game() {
+        let mut rt = hosted_runtime(2, 3);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        for _ in 0..3 {
+            question(&mut rt, "0");
+        }
+        answer(&mut rt, "0");
+        let resps = host(&mut rt, "0", HostCommand::SkipRound);
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::RoundSkipped { round_num: 0 },
+                Response::NewRound { round_num: 1, .. }
+            ]
+        ));
+        assert_eq!(rt.room().player(0).unwrap().points, 0);
+        let resps = host(&mut rt, "0", HostCommand::EndGame);
+        assert_eq!(resps, vec![Response::GameFinished]);
+        assert!(rt.is_finished());
+    }
+
+    #[test]
+    fn host_can_change_limits_before_the_game() {
+        let mut rt = hosted_runtime(3, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let limits = |players_limit, rounds_limit| HostCommand::ChangeLimits {
+            players_limit,
+            rounds_limit,
+        };
+        assert_err(
+            &host(&mut rt, "0", limits(Some(1), None)),
+            ErrResponse::InvalidLimits,
+        );
+        assert_err(
+            &host(&mut rt, "0", limits(None, Some(0))),
+            ErrResponse::InvalidLimits,
+        );
+        let resps = host(&mut rt, "0", limits(Some(2), Some(2)));
+        assert_eq!(
+            resps[0],
+            Response::LimitsChanged {
+                players_limit: 2,
+                rounds_limit: 2
+            }
+        );
+        assert_eq!(rt.room().state, RoomState::AcceptingQuestions);
+        assert_eq!(rt.room().rounds_limit, 2);
+        assert_err(
+            &host(&mut rt, "0", limits(Some(3), None)),
+            ErrResponse::UnexpectedRequest,
+        );
+    }
+
+    #[test]
+    fn audience_votes_only_when_it_counts() {
+        let mut rt = runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        answer(&mut rt, "1");
+        let of_first = answer_id_of(&rt, 0);
+        assert!(send(&mut rt, "s0", Request::SelectAnswer { answer: of_first }).is_empty());
+        assert!(rt
+            .room()
+            .curr_round
+            .as_ref()
+            .unwrap()
+            .audience_polls
+            .is_empty());
+
+        let mut room = Room::new([0; 12], 0, 2, 1);
+        room.scoring = vec![ScoringRule::PerVote, ScoringRule::AudienceVotes(3)];
+        let mut rt = Runtime::new(room, test_config());
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        // no voting before polling starts
+        send(&mut rt, "s0", Request::SelectAnswer { answer: 0 });
+        answer(&mut rt, "1");
+        let of_first = answer_id_of(&rt, 0);
+        let of_second = answer_id_of(&rt, 1);
+        send(&mut rt, "s0", Request::SelectAnswer { answer: of_first });
+        // spectators cannot change their mind nor act as players
+        send(&mut rt, "s0", Request::SelectAnswer { answer: of_second });
+        assert!(send(&mut rt, "s1", Request::Disconnecting).is_empty());
+        assert_eq!(
+            rt.room().curr_round.as_ref().unwrap().audience_polls.len(),
+            1
+        );
+        vote(&mut rt, "0", of_second);
+        vote(&mut rt, "1", of_first);
+        assert_eq!(rt.room().player(0).unwrap().points, 4);
+        assert_eq!(rt.room().player(1).unwrap().points, 1);
+    }
+
+    #[test]
+    fn authors_are_revealed_only_in_results() {
+        let mut rt = runtime(3, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        answer(&mut rt, "1");
+        let polled = match answer(&mut rt, "2").pop() {
+            Some(Response::PollingStarted { answers }) => answers,
+            resps => panic!("expected polling to start, got {:?}", resps),
+        };
+        let ids: HashSet<_> = polled.iter().map(|a| a.id).collect();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.contains(&answer_id_of(&rt, 2)));
+        let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 1));
+        vote(&mut rt, "1", of_first);
+        vote(&mut rt, "2", of_first);
+        let resps = vote(&mut rt, "0", of_second);
+        let table = match resps.as_slice() {
+            [_, Response::GameScore(table), Response::GameFinished] => table,
+            resps => panic!("expected scores, got {:?}", resps),
+        };
+        assert_eq!(table.answers.len(), 3);
+        assert_eq!(table.answers[0].id, of_first);
+        assert_eq!(table.answers[0].author, 0);
+        assert_eq!(table.answers[0].votes, 2);
+    }
+
+    /// Credentials of rooms made with `Room::new`.
+    fn no_credentials() -> Credentials {
+        Credentials {
+            password: 0,
+            host_token: 0,
+            token_key: 0,
+        }
+    }
+
+    #[test]
+    fn room_is_rebuilt_from_its_log() {
+        let mut rt = timed_runtime(3, 2, IdlePolicy::Kick);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        join(&mut rt, "2");
+        assert_err(&vote(&mut rt, "0", 0), ErrResponse::UnexpectedRequest);
+        question(&mut rt, "0");
+        time_out(&mut rt);
+        answer(&mut rt, "0");
+        send(&mut rt, "2", Request::Disconnecting);
+        let deadline = rt.away[&2];
+        resps_after(&mut rt, deadline);
+        assert!(!rt.room().has_player(2));
+        let log = rt.take_log();
+        // rejected requests and disconnects within the grace time are not logged
+        assert_eq!(log.len(), 8);
+        assert!(log
+            .iter()
+            .enumerate()
+            .all(|(i, entry)| entry.seq == i as u64));
+        let events = log.into_iter().map(|entry| entry.event).collect();
+        let replayed = <Runtime as GameMode>::replay(events, &no_credentials()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&replayed).unwrap(),
+            serde_json::to_value(rt.room()).unwrap()
+        );
+        assert!(replay(vec![RoomEvent::PhaseExpired {
+            phase: Phase::Questions
+        }])
+        .is_none());
+    }
+
+    #[test]
+    fn credentials_are_left_out_of_the_log() {
+        let credentials = Credentials {
+            password: 21,
+            host_token: HOST_TOKEN,
+            token_key: 22,
+        };
+        let mut room = Room::new([0; 12], credentials.password, 3, 1);
+        room.host_token = Some(credentials.host_token);
+        room.token_key = credentials.token_key;
+        let mut rt = Runtime::new(room, test_config());
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        host(&mut rt, "0", HostCommand::Kick { player: 1 });
+        let log = rt.take_log();
+        let logged = serde_json::to_string(&log).unwrap();
+        for secret in &["\"pass\":21", "\"host_token\":7", "\"token_key\":22"] {
+            assert!(!logged.contains(secret));
+        }
+        for player in 0..2 {
+            let token = model::player_token(credentials.token_key, player);
+            assert!(!logged.contains(&token.to_string()));
+        }
+        let events = log.into_iter().map(|entry| entry.event).collect();
+        let replayed = <Runtime as GameMode>::replay(events, &credentials).unwrap();
+        assert_eq!(
+            serde_json::to_value(&replayed).unwrap(),
+            serde_json::to_value(rt.room()).unwrap()
+        );
+    }
+
+    fn seeded_runtime(seed: u64) -> Runtime {
+        let mut room = Room::new([0; 12], 0, 3, 2);
+        room.seed = seed;
+        Runtime::new(room, test_config())
+    }
+
+    fn start_seeded_game(seed: u64) -> Runtime {
+        let mut rt = seeded_runtime(seed);
+        for player in &["0", "1", "2"] {
+            join(&mut rt, player);
+        }
+        question(&mut rt, "0");
+        question(&mut rt, "1");
+        rt
+    }
+
+    /// Every player answers, returns the ids their answers got for polling.
+    fn answer_all(rt: &mut Runtime) -> Vec<AnswerId> {
+        for player in &["0", "1", "2"] {
+            answer(rt, player);
+        }
+        (0..3).map(|player| answer_id_of(rt, player)).collect()
+    }
+
+    #[test]
+    fn answer_ids_come_from_the_seed() {
+        let ids = answer_all(&mut start_seeded_game(1));
+        assert_eq!(ids, answer_all(&mut start_seeded_game(1)));
+        assert_ne!(ids, answer_all(&mut start_seeded_game(2)));
+        // neighbouring seeds do not share their draws
+        let mut first = Room::new([0; 12], 0, 1, 1);
+        first.seed = 1;
+        first.rng();
+        let mut second = Room::new([0; 12], 0, 1, 1);
+        second.seed = 2;
+        assert_ne!(first.rng().gen::<u64>(), second.rng().gen::<u64>());
+    }
+
+    #[test]
+    fn whole_game_is_replayed_exactly() {
+        let mut rt = start_seeded_game(3);
+        let mut log = rt.take_log();
+        for _ in 0..2 {
+            let ids = answer_all(&mut rt);
+            vote(&mut rt, "0", ids[1]);
+            vote(&mut rt, "1", ids[2]);
+            vote(&mut rt, "2", ids[0]);
+            log.extend(rt.take_log());
+        }
+        assert!(rt.is_finished());
+        let events = log.into_iter().map(|entry| entry.event).collect();
+        let replayed = <Runtime as GameMode>::replay(events, &no_credentials()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&replayed).unwrap(),
+            serde_json::to_value(rt.room()).unwrap()
+        );
+    }
+
+    #[test]
+    fn rematch_keeps_the_players_and_archives_the_game() {
+        let mut rt = rematch_runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let rematch = HostCommand::Rematch {
+            players_limit: None,
+            rounds_limit: Some(2),
+        };
+        assert_err(
+            &host(&mut rt, "0", rematch.clone()),
+            ErrResponse::UnexpectedRequest,
+        );
+        let resps = play_short_game(&mut rt);
+        assert!(resps.contains(&Response::GameFinished));
+        assert_eq!(
+            resps.last(),
+            Some(&Response::TimeLeft {
+                phase: Phase::Rematch,
+                secs: 60
+            })
+        );
+        assert_eq!(rt.room().state, RoomState::Finished);
+        assert!(!rt.is_finished());
+        assert_eq!(rt.timer.as_ref().unwrap().phase, Phase::Rematch);
+        let resps = host(&mut rt, "0", rematch);
+        assert!(matches!(
+            resps.as_slice(),
+            [Response::RematchStarted {
+                players_limit: 2,
+                rounds_limit: 2
+            }]
+        ));
+        let room = rt.room();
+        assert_eq!(room.state, RoomState::AcceptingQuestions);
+        assert_eq!(room.players.len(), 2);
+        assert!(room.players.iter().all(|p| p.points == 0));
+        assert!(room.questions.is_empty() && room.past_rounds.is_empty());
+        assert_eq!(room.past_games.len(), 1);
+        assert_eq!(room.past_games[0].rounds.len(), 1);
+        assert!(room.past_games[0].players.iter().all(|p| p.points == 1));
+        question(&mut rt, "1");
+        question(&mut rt, "0");
+        assert_eq!(rt.room().state, RoomState::Playing);
+    }
+
+    #[test]
+    fn rematch_can_let_more_players_in() {
+        let mut rt = rematch_runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        play_short_game(&mut rt);
+        let too_few = HostCommand::Rematch {
+            players_limit: Some(1),
+            rounds_limit: None,
+        };
+        assert_err(&host(&mut rt, "0", too_few), ErrResponse::InvalidLimits);
+        let no_rounds = HostCommand::Rematch {
+            players_limit: None,
+            rounds_limit: Some(0),
+        };
+        assert_err(&host(&mut rt, "0", no_rounds), ErrResponse::InvalidLimits);
+        let more = HostCommand::Rematch {
+            players_limit: Some(3),
+            rounds_limit: None,
+        };
+        host(&mut rt, "0", more);
+        assert_eq!(rt.room().state, RoomState::AcceptingPlayers);
+        join(&mut rt, "2");
+        assert_eq!(rt.room().state, RoomState::AcceptingQuestions);
+    }
+
+    #[test]
+    fn finished_room_closes_without_a_rematch() {
+        let mut rt = rematch_runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        play_short_game(&mut rt);
+        let resps = time_out(&mut rt);
+        assert!(matches!(resps.as_slice(), [Response::RoomClosed]));
+        assert!(rt.is_finished());
+        // or once the host says so
+        let mut rt = rematch_runtime(2, 1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        play_short_game(&mut rt);
+        host(&mut rt, "0", HostCommand::EndGame);
+        assert!(rt.is_finished());
+        // or right away if the room has no host
+        let mut rt = rematch_runtime(2, 1);
+        rt.rd.host_token = None;
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        play_short_game(&mut rt);
+        assert_eq!(rt.room().state, RoomState::Dead);
+        assert!(rt.is_finished());
+    }
+
+    fn team_runtime(players_limit: usize, teams: usize, team_answers: bool) -> Runtime {
+        let mut room = Room::new([0; 12], 0, players_limit, 1);
+        room.host_token = Some(HOST_TOKEN);
+        room.teams = teams;
+        room.team_answers = team_answers;
+        Runtime::new(room, test_config())
+    }
+
+    fn game_score(resps: &[Response]) -> &ScoreTable {
+        resps
+            .iter()
+            .find_map(|resp| match resp {
+                Response::GameScore(table) => Some(table),
+                _ => None,
+            })
+            .expect("no game score")
+    }
+
+    #[test]
+    fn players_are_split_into_teams() {
+        let mut rt = team_runtime(5, 2, false);
+        for player in &["0", "1", "2", "3"] {
+            join(&mut rt, player);
+        }
+        let teams: Vec<_> = rt.room().players.iter().map(|p| p.team).collect();
+        assert_eq!(teams, vec![Some(0), Some(1), Some(0), Some(1)]);
+        let resps = host(&mut rt, "0", HostCommand::AssignTeam { player: 3, team: 0 });
+        assert!(matches!(
+            resps.as_slice(),
+            [Response::TeamAssigned { player: 3, team: 0 }]
+        ));
+        assert_eq!(rt.room().team_of(3), Some(0));
+        assert_err(
+            &host(&mut rt, "0", HostCommand::AssignTeam { player: 3, team: 2 }),
+            ErrResponse::NoSuchTeam,
+        );
+        // the next player evens the teams out
+        join(&mut rt, "4");
+        assert_eq!(rt.room().team_of(4), Some(1));
+        // there are no teams to assign to without team mode
+        let mut rt = hosted_runtime(2, 1);
+        join(&mut rt, "0");
+        assert_eq!(rt.room().team_of(0), None);
+        assert_err(
+            &host(&mut rt, "0", HostCommand::AssignTeam { player: 0, team: 0 }),
+            ErrResponse::NoSuchTeam,
+        );
+    }
+
+    #[test]
+    fn every_team_keeps_a_slot_when_limits_change() {
+        let mut rt = team_runtime(4, 3, false);
+        join(&mut rt, "0");
+        let limits = HostCommand::ChangeLimits {
+            players_limit: Some(2),
+            rounds_limit: None,
+        };
+        assert_err(&host(&mut rt, "0", limits), ErrResponse::InvalidLimits);
+        assert_eq!(rt.room().players_limit, 4);
+    }
+
+    #[test]
+    fn votes_for_own_team_are_rejected() {
+        let mut rt = team_runtime(4, 2, false);
+        for player in &["0", "1", "2", "3"] {
+            join(&mut rt, player);
+        }
+        question(&mut rt, "0");
+        for player in &["0", "1", "2", "3"] {
+            answer(&mut rt, player);
+        }
+        let ids: Vec<_> = (0..4).map(|player| answer_id_of(&rt, player)).collect();
+        assert_err(&vote(&mut rt, "0", ids[2]), ErrResponse::OwnTeam);
+        vote(&mut rt, "0", ids[1]);
+        vote(&mut rt, "2", ids[1]);
+        vote(&mut rt, "1", ids[0]);
+        let resps = vote(&mut rt, "3", ids[2]);
+        let table = game_score(&resps);
+        assert_eq!(table.scores[0].id, 1);
+        assert_eq!(table.scores[0].team, Some(1));
+        assert_eq!(table.teams.len(), 2);
+        assert_eq!(table.teams[0].id, 0);
+        assert_eq!(table.teams[0].players, vec![0, 2]);
+        assert_eq!(table.teams[0].points, 2);
+        assert_eq!(table.teams[1].points, 2);
+    }
+
+    #[test]
+    fn teams_can_answer_together() {
+        let mut rt = team_runtime(4, 2, true);
+        for player in &["0", "1", "2", "3"] {
+            join(&mut rt, player);
+        }
+        question(&mut rt, "0");
+        answer(&mut rt, "0");
+        assert_err(&answer(&mut rt, "2"), ErrResponse::AnswerAlreadySent);
+        let resps = answer(&mut rt, "3");
+        assert!(matches!(
+            resps.as_slice(),
+            [
+                Response::AnswerAdded { .. },
+                Response::PollingStarted { .. }
+            ]
+        ));
+        let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 3));
+        vote(&mut rt, "0", of_second);
+        vote(&mut rt, "2", of_second);
+        vote(&mut rt, "1", of_first);
+        let resps = vote(&mut rt, "3", of_first);
+        let table = game_score(&resps);
+        assert_eq!(table.teams[0].points, 2);
+        assert_eq!(table.teams[1].points, 2);
+        assert_eq!(rt.room().player(3).unwrap().points, 2);
+        assert_eq!(rt.room().player(1).unwrap().points, 0);
+    }
+
+    fn tie_break_runtime(players_limit: usize, tie_break: TieBreak) -> Runtime {
+        let mut room = Room::new([0; 12], 0, players_limit, 1);
+        room.tie_break = tie_break;
+        // the first one is the question asked in the regular round
+        room.spare_questions = ["WHY 0 NUMBER 0?", "who?"]
+            .iter()
+            .enumerate()
+            .map(|(id, content)| Question {
+                id: id + 1,
+                player_id: None,
+                content: content.to_string(),
+            })
+            .collect();
+        Runtime::new(room, test_config())
+    }
+
+    #[test]
+    fn tied_players_share_the_victory() {
+        let mut rt = tie_break_runtime(2, TieBreak::SharedVictory);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let resps = play_short_game(&mut rt);
+        assert_eq!(game_score(&resps).winners, vec![0, 1]);
+        assert!(matches!(resps.last(), Some(Response::GameFinished)));
+    }
+
+    #[test]
+    fn sudden_death_is_played_by_the_tied_players_only() {
+        let mut rt = tie_break_runtime(4, TieBreak::SuddenDeath);
+        for player in &["0", "1", "2", "3"] {
+            join(&mut rt, player);
+        }
+        question(&mut rt, "0");
+        for player in &["0", "1", "2", "3"] {
+            answer(&mut rt, player);
+        }
+        let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 1));
+        vote(&mut rt, "0", of_second);
+        vote(&mut rt, "1", of_first);
+        vote(&mut rt, "2", of_first);
+        let resps = vote(&mut rt, "3", of_second);
+        assert!(game_score(&resps).winners.is_empty());
+        assert!(matches!(
+            &resps[resps.len() - 2..],
+            [
+                Response::SuddenDeath { players },
+                Response::NewRound { round_num: 1, question }
+            ] if players == &vec![0, 1] && question == "who?"
+        ));
+        assert_err(&answer(&mut rt, "2"), ErrResponse::UnexpectedRequest);
+        answer(&mut rt, "0");
+        let resps = answer(&mut rt, "1");
+        assert!(matches!(
+            resps.last(),
+            Some(Response::PollingStarted { .. })
+        ));
+        let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 1));
+        vote(&mut rt, "0", of_second);
+        vote(&mut rt, "1", of_first);
+        vote(&mut rt, "2", of_first);
+        let resps = vote(&mut rt, "3", of_first);
+        assert_eq!(game_score(&resps).winners, vec![0]);
+        assert!(matches!(resps.last(), Some(Response::GameFinished)));
+        assert!(rt.room().sudden_death.is_empty());
+    }
+
+    #[test]
+    fn tie_is_shared_once_questions_run_out() {
+        let mut rt = tie_break_runtime(2, TieBreak::SuddenDeath);
+        rt.rd.spare_questions.truncate(1);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let resps = play_short_game(&mut rt);
+        assert_eq!(game_score(&resps).winners, vec![0, 1]);
+        assert!(matches!(resps.last(), Some(Response::GameFinished)));
+        assert!(rt.room().sudden_death.is_empty());
+    }
+
+    #[test]
+    fn earliest_answer_breaks_the_tie() {
+        let mut rt = tie_break_runtime(2, TieBreak::EarliestAnswer);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        question(&mut rt, "0");
+        answer(&mut rt, "1");
+        answer(&mut rt, "0");
+        let (of_first, of_second) = (answer_id_of(&rt, 0), answer_id_of(&rt, 1));
+        vote(&mut rt, "0", of_second);
+        let resps = vote(&mut rt, "1", of_first);
+        assert_eq!(game_score(&resps).winners, vec![1]);
+    }
+
+    #[test]
+    fn questions_and_answers_are_validated() {
+        let mut config = test_config();
+        config.content.banned_words = vec!["darn".into()];
+        let mut rt = Runtime::new(Room::new([0; 12], 0, 2, 1), config);
+        join(&mut rt, "0");
+        join(&mut rt, "1");
+        let mut add = |player, content: &str| {
+            let content = content.into();
+            send(&mut rt, player, Request::AddQuestion { content })
+        };
+        assert_err(&add("0", " \n "), ErrResponse::EmptyContent);
+        assert_err(&add("0", &"?".repeat(201)), ErrResponse::ContentTooLong);
+        assert_err(
+            &add("0", "why, darn it?"),
+            ErrResponse::InappropriateContent,
+        );
+        add("0", "  why   not? ");
+        assert_eq!(rt.room().questions[0].content, "why not?");
+        let mut rt = runtime(3, 1);
+        for player in &["0", "1", "2"] {
+            join(&mut rt, player);
         }
-        service::Command::Skip
+        question(&mut rt, "0");
+        let mut add = |player, content: &str| {
+            let content = content.into();
+            send(&mut rt, player, Request::AddAnswer { content })
+        };
+        add("0", "Because");
+        assert_err(&add("1", "because "), ErrResponse::DuplicateContent);
+        assert!(!rt
+            .room()
+            .curr_round
+            .as_ref()
+            .unwrap()
+            .answers
+            .contains_key(&1));
     }
 }
@@ -0,0 +1,387 @@
+//! Scoring of the finished rounds.
+//!
+//! Every room keeps a list of `ScoringRule`s chosen when it was created.
+//! Each rule is turned into a `ScoringPolicy` and all of them are consulted
+//! in order once a round leaves polling, so the later policies can see
+//! and build upon the points given by the earlier ones.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{ScoreEntry, ScoreReason};
+use crate::room::model::{AnswerId, PlayerId, Round, TeamId};
+
+/// Points given to each player in a round along with the reasons.
+pub type Breakdown = HashMap<PlayerId, Vec<ScoreEntry>>;
+
+pub struct RoundContext<'a> {
+    pub players: &'a [PlayerId], // players still in the room
+    // players who answered on their own or through their team
+    pub answered: &'a [PlayerId],
+    pub teams: &'a HashMap<PlayerId, TeamId>, // empty if everybody plays on their own
+    pub last_round: bool,
+}
+
+pub trait ScoringPolicy: Send + Sync {
+    fn score(&self, round: &Round, ctx: &RoundContext<'_>, breakdown: &mut Breakdown);
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScoringRule {
+    PerVote,                   // a point for every vote received
+    WinnerTakesAll,            // every vote cast goes to the most voted answer
+    UnanimousBonus(i64),       // when everybody who could voted for the same answer
+    NoAnswerPenalty(i64),      // taken from players who did not answer
+    FinalRoundMultiplier(i64), // multiplies points gained in the last round
+    AudienceVotes(i64),        // split between answers by the audience's votes
+}
+
+impl ScoringRule {
+    pub fn policy(&self) -> Box<dyn ScoringPolicy> {
+        match *self {
+            ScoringRule::PerVote => Box::new(PerVote),
+            ScoringRule::WinnerTakesAll => Box::new(WinnerTakesAll),
+            ScoringRule::UnanimousBonus(points) => Box::new(UnanimousBonus { points }),
+            ScoringRule::NoAnswerPenalty(points) => Box::new(NoAnswerPenalty { points }),
+            ScoringRule::FinalRoundMultiplier(factor) => Box::new(FinalRoundMultiplier { factor }),
+            ScoringRule::AudienceVotes(weight) => Box::new(AudienceVotes { weight }),
+        }
+    }
+}
+
+pub fn default_rules() -> Vec<ScoringRule> {
+    vec![ScoringRule::PerVote]
+}
+
+/// Runs every policy on the round. Players who left the room get nothing.
+pub fn score_round(
+    policies: &[Box<dyn ScoringPolicy>],
+    round: &Round,
+    ctx: &RoundContext<'_>,
+) -> Breakdown {
+    let mut breakdown = Breakdown::new();
+    for policy in policies {
+        policy.score(round, ctx, &mut breakdown);
+    }
+    breakdown.retain(|id, _| ctx.players.contains(id));
+    breakdown
+}
+
+pub struct PerVote;
+
+impl ScoringPolicy for PerVote {
+    fn score(&self, round: &Round, _ctx: &RoundContext<'_>, breakdown: &mut Breakdown) {
+        for (author, votes) in votes_per_author(round) {
+            add(breakdown, author, ScoreReason::Votes, votes);
+        }
+    }
+}
+
+pub struct WinnerTakesAll;
+
+impl ScoringPolicy for WinnerTakesAll {
+    fn score(&self, round: &Round, _ctx: &RoundContext<'_>, breakdown: &mut Breakdown) {
+        let votes = votes_per_author(round);
+        let best = match votes.values().max() {
+            Some(best) => *best,
+            None => return,
+        };
+        // ties share the win
+        let all_votes = round.polls.len() as i64;
+        for (author, _) in votes.into_iter().filter(|(_, v)| *v == best) {
+            add(breakdown, author, ScoreReason::RoundWinner, all_votes);
+        }
+    }
+}
+
+pub struct UnanimousBonus {
+    points: i64,
+}
+
+impl ScoringPolicy for UnanimousBonus {
+    fn score(&self, round: &Round, ctx: &RoundContext<'_>, breakdown: &mut Breakdown) {
+        for (author, votes) in votes_per_author(round) {
+            if votes < 2 {
+                continue;
+            }
+            // teammates cannot vote for the answer so their votes are left out
+            let team = ctx.teams.get(&author);
+            let teammate = |player: PlayerId| {
+                player != author && team.is_some() && ctx.teams.get(&player) == team
+            };
+            let all_votes_for_author = round
+                .polls
+                .keys()
+                .filter(|voter| !teammate(**voter))
+                .all(|voter| voted_for(round, *voter) == Some(author));
+            // players who did not vote break the unanimity too,
+            // only the author cannot vote for their own answer
+            let everybody_voted = ctx
+                .players
+                .iter()
+                .filter(|player| **player != author && !teammate(**player))
+                .all(|player| voted_for(round, *player) == Some(author));
+            if all_votes_for_author && everybody_voted {
+                add(breakdown, author, ScoreReason::Unanimous, self.points);
+                return;
+            }
+        }
+    }
+}
+
+pub struct NoAnswerPenalty {
+    points: i64,
+}
+
+impl ScoringPolicy for NoAnswerPenalty {
+    fn score(&self, _round: &Round, ctx: &RoundContext<'_>, breakdown: &mut Breakdown) {
+        for player in ctx.players {
+            if !ctx.answered.contains(player) {
+                add(breakdown, *player, ScoreReason::NoAnswer, -self.points);
+            }
+        }
+    }
+}
+
+pub struct FinalRoundMultiplier {
+    factor: i64,
+}
+
+impl ScoringPolicy for FinalRoundMultiplier {
+    fn score(&self, _round: &Round, ctx: &RoundContext<'_>, breakdown: &mut Breakdown) {
+        if !ctx.last_round {
+            return;
+        }
+        for entries in breakdown.values_mut() {
+            let gained: i64 = entries.iter().map(|e| e.points).sum();
+            let points = gained * (self.factor - 1);
+            if points != 0 {
+                entries.push(ScoreEntry {
+                    reason: ScoreReason::FinalRound,
+                    points,
+                });
+            }
+        }
+    }
+}
+
+/// The whole audience is worth `weight` points,
+/// each answer gets the part of it the audience voted for.
+pub struct AudienceVotes {
+    weight: i64,
+}
+
+impl ScoringPolicy for AudienceVotes {
+    fn score(&self, round: &Round, _ctx: &RoundContext<'_>, breakdown: &mut Breakdown) {
+        let all_votes = round.audience_polls.len() as i64;
+        for (author, votes) in tally(round, round.audience_polls.values()) {
+            let points = self.weight * votes / all_votes;
+            add(breakdown, author, ScoreReason::Audience, points);
+        }
+    }
+}
+
+fn votes_per_author(round: &Round) -> HashMap<PlayerId, i64> {
+    tally(round, round.polls.values())
+}
+
+/// Author of the answer the player voted for.
+fn voted_for(round: &Round, voter: PlayerId) -> Option<PlayerId> {
+    let answer_id = round.polls.get(&voter)?;
+    author_of(round, *answer_id)
+}
+
+fn author_of(round: &Round, answer_id: AnswerId) -> Option<PlayerId> {
+    round
+        .answers
+        .values()
+        .find(|a| a.id == answer_id)
+        .map(|a| a.player_id)
+}
+
+fn tally<'a>(round: &Round, polls: impl Iterator<Item = &'a AnswerId>) -> HashMap<PlayerId, i64> {
+    let mut votes = HashMap::new();
+    for answer_id in polls {
+        if let Some(author) = author_of(round, *answer_id) {
+            *votes.entry(author).or_insert(0) += 1;
+        }
+    }
+    votes
+}
+
+fn add(breakdown: &mut Breakdown, player: PlayerId, reason: ScoreReason, points: i64) {
+    if points != 0 {
+        breakdown
+            .entry(player)
+            .or_default()
+            .push(ScoreEntry { reason, points });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::model::{Answer, Question, RoundState};
+
+    /// Round in which player `i` answered if `answered[i]`
+    /// and voted for the answer of `votes[i]`.
+    fn round(answered: &[bool], votes: &[(PlayerId, PlayerId)]) -> Round {
+        let mut round = Round {
+            round_num: 0,
+            state: RoundState::Polling,
+            question: Question {
+                id: 0,
+                player_id: Some(0),
+                content: "why?".into(),
+            },
+            answers: Default::default(),
+            polls: Default::default(),
+            audience_polls: Default::default(),
+        };
+        for (player, _) in answered.iter().enumerate().filter(|(_, a)| **a) {
+            let answer = Answer {
+                id: player,
+                player_id: player,
+                content: "because".into(),
+                team: None,
+                received: player,
+            };
+            round.answers.insert(player, answer);
+        }
+        for (voter, author) in votes {
+            round.polls.insert(*voter, *author);
+        }
+        round
+    }
+
+    fn score(rules: &[ScoringRule], round: &Round, last_round: bool) -> HashMap<PlayerId, i64> {
+        score_among(rules, round, &[0, 1, 2], last_round)
+    }
+
+    fn score_among(
+        rules: &[ScoringRule],
+        round: &Round,
+        players: &[PlayerId],
+        last_round: bool,
+    ) -> HashMap<PlayerId, i64> {
+        score_in_teams(rules, round, players, &HashMap::new(), last_round)
+    }
+
+    fn score_in_teams(
+        rules: &[ScoringRule],
+        round: &Round,
+        players: &[PlayerId],
+        teams: &HashMap<PlayerId, TeamId>,
+        last_round: bool,
+    ) -> HashMap<PlayerId, i64> {
+        let policies: Vec<_> = rules.iter().map(ScoringRule::policy).collect();
+        let answered: Vec<_> = round.answers.keys().copied().collect();
+        let ctx = RoundContext {
+            players,
+            answered: &answered,
+            teams,
+            last_round,
+        };
+        score_round(&policies, round, &ctx)
+            .into_iter()
+            .map(|(id, entries)| (id, entries.iter().map(|e| e.points).sum()))
+            .collect()
+    }
+
+    #[test]
+    fn votes_give_points() {
+        let round = round(&[true, true, true], &[(0, 1), (1, 0), (2, 1)]);
+        let points = score(&[ScoringRule::PerVote], &round, false);
+        assert_eq!(points[&0], 1);
+        assert_eq!(points[&1], 2);
+        assert!(!points.contains_key(&2));
+    }
+
+    #[test]
+    fn winner_takes_all_votes() {
+        let round = round(&[true, true, true], &[(0, 1), (1, 0), (2, 1)]);
+        let points = score(&[ScoringRule::WinnerTakesAll], &round, false);
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[&1], 3);
+    }
+
+    #[test]
+    fn unanimous_votes_get_a_bonus() {
+        let rules = [ScoringRule::PerVote, ScoringRule::UnanimousBonus(5)];
+        let unanimous = round(&[true, true, true], &[(0, 2), (1, 2), (2, 2)]);
+        assert_eq!(score(&rules, &unanimous, false)[&2], 8);
+        let split = round(&[true, true, true], &[(0, 2), (1, 2), (2, 0)]);
+        assert_eq!(score(&rules, &split, false)[&2], 2);
+    }
+
+    #[test]
+    fn abstaining_breaks_unanimity() {
+        let rules = [ScoringRule::PerVote, ScoringRule::UnanimousBonus(5)];
+        let players = [0, 1, 2, 3];
+        let mut round = round(&[true, true, true, true], &[(0, 3), (1, 3)]);
+        assert_eq!(score_among(&rules, &round, &players, false)[&3], 2);
+        round.polls.insert(2, 3);
+        assert_eq!(score_among(&rules, &round, &players, false)[&3], 8);
+    }
+
+    #[test]
+    fn teammates_do_not_break_unanimity() {
+        let rules = [ScoringRule::PerVote, ScoringRule::UnanimousBonus(5)];
+        let players = [0, 1, 2, 3];
+        let teams: HashMap<_, _> = vec![(0, 0), (1, 1), (2, 0), (3, 1)].into_iter().collect();
+        // player 2 cannot vote for their teammate's answer
+        let unanimous = round(&[true, true, true, true], &[(1, 0), (3, 0), (2, 1)]);
+        assert_eq!(
+            score_in_teams(&rules, &unanimous, &players, &teams, false)[&0],
+            7
+        );
+        // everybody else still has to vote for it
+        let abstained = round(&[true, true, true, true], &[(1, 0), (2, 1)]);
+        assert_eq!(
+            score_in_teams(&rules, &abstained, &players, &teams, false)[&0],
+            1
+        );
+    }
+
+    #[test]
+    fn missing_answers_are_penalized() {
+        let round = round(&[true, false, true], &[(0, 2), (1, 2), (2, 0)]);
+        let rules = [ScoringRule::PerVote, ScoringRule::NoAnswerPenalty(2)];
+        assert_eq!(score(&rules, &round, false)[&1], -2);
+    }
+
+    #[test]
+    fn last_round_is_multiplied() {
+        let round = round(&[true, false, true], &[(0, 2), (1, 2), (2, 0)]);
+        let rules = [
+            ScoringRule::PerVote,
+            ScoringRule::NoAnswerPenalty(1),
+            ScoringRule::FinalRoundMultiplier(3),
+        ];
+        let points = score(&rules, &round, true);
+        assert_eq!(points[&0], 3);
+        assert_eq!(points[&1], -3);
+        assert_eq!(points[&2], 6);
+        assert_eq!(score(&rules, &round, false)[&2], 2);
+    }
+
+    #[test]
+    fn audience_votes_are_weighted() {
+        let mut round = round(&[true, true, true], &[(0, 1), (1, 0), (2, 1)]);
+        for (spectator, author) in [(0, 0), (1, 0), (2, 0), (3, 2)].iter() {
+            round.audience_polls.insert(*spectator, *author);
+        }
+        let rules = [ScoringRule::PerVote, ScoringRule::AudienceVotes(4)];
+        let points = score(&rules, &round, false);
+        assert_eq!(points[&0], 4);
+        assert_eq!(points[&1], 2);
+        assert_eq!(points[&2], 1);
+    }
+
+    #[test]
+    fn players_who_left_get_nothing() {
+        let round = round(&[true, true, false, true], &[(0, 3), (1, 3)]);
+        let points = score(&[ScoringRule::PerVote], &round, false);
+        assert!(points.is_empty());
+    }
+}
@@ -1,21 +1,105 @@
 use serde::{Deserialize, Serialize};
 
+use crate::room::{
+    mode::GameKind,
+    model::{PlayerId, PlayerToken, RoomState, SpectatorId, TieBreak},
+    scoring::{self, ScoringRule},
+};
+
 #[derive(Debug, Deserialize)]
 pub struct NewRoomReq {
+    #[serde(default)]
+    pub mode: GameKind,
     pub players_limit: usize,
     pub rounds_limit: usize,
+    // applied in order once every round is over
+    #[serde(default = "scoring::default_rules")]
+    pub scoring: Vec<ScoringRule>,
+    // ids of the question packs to take questions from
+    #[serde(default)]
+    pub packs: Vec<String>,
+    // how many questions are taken from the packs, every one by default,
+    // the rest has to be submitted by the players
+    #[serde(default)]
+    pub pack_questions: Option<usize>,
+    // how many teams the players are split into, 0 plays without teams
+    #[serde(default)]
+    pub teams: usize,
+    // every team gives a single answer instead of one per player
+    #[serde(default)]
+    pub team_answers: bool,
+    // what happens if there is a tie for the first place once all rounds are played
+    #[serde(default)]
+    pub tie_break: TieBreak,
 }
 
 #[derive(Debug, Serialize)]
 pub struct NewRoomResp {
     pub id: String,
     pub password: i64,
+    pub host_token: PlayerToken, // lets whoever holds it moderate the room
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPlayerReq {
+    pub id: String, // room's id as returned in NewRoomResp
+    pub password: i64,
+    pub name: String,
+    // given by an earlier join, players banned from the room
+    // are not let back in with it
+    #[serde(default)]
+    pub token: Option<PlayerToken>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct NewSpectatorReq {
+    pub id: String, // room's id as returned in NewRoomResp
+    pub password: i64,
+    // players banned from the room cannot watch it with their token either
+    #[serde(default)]
+    pub token: Option<PlayerToken>,
+}
 
 #[derive(Debug, Serialize)]
-pub struct NewPlayerReq {
-    id: usize,
-    password: u64,
-    name: String,
+pub struct NewSpectatorResp {
+    pub spectator_id: SpectatorId,
+    pub mqtt_host: String,
+    pub username: String,
+    pub password: String,
+    pub room_topic: String, // the same broadcasts players get
+    pub vote_topic: String, // where audience votes are sent to
+}
+
+/// Everything the player needs to connect to the mqtt broker
+/// and join the room's runtime.
+#[derive(Debug, Serialize)]
+pub struct NewPlayerResp {
+    pub player_id: PlayerId,
+    pub token: PlayerToken,
+    pub mqtt_host: String,
+    pub username: String,
+    pub password: String,
+    pub read_topic: String, // private responses for this player only
+    pub write_topic: String,
+    pub room_topic: String, // responses broadcast to the whole room
+    pub will_topic: String, // where the player's last will has to be set
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomStatus {
+    pub id: String,
+    pub mode: GameKind,
+    pub state: RoomState,
+    pub players: usize,
+    pub players_limit: usize,
+    pub rounds_limit: usize,
+    pub round: Option<usize>, // number of the round being played
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackInfo {
+    pub id: String,
+    pub language: String,
+    pub tags: Vec<String>,
+    pub questions: usize, // number of questions in the pack
 }
@@ -1,35 +1,67 @@
-use std::{pin::Pin, time::Duration};
+use std::{convert::TryInto, time::Duration};
 
 use crate::{
     config::Config,
-    message,
-    repository::{self, DataRepository, RepReq, RepReqChannel, RepResp, RoomEntry},
-    room,
-    room::model::Room,
+    packs::PackLibrary,
+    repository::{
+        self, DataRepository, RepError, RepReq, RepReqChannel, RepResp, SavedRoom, UserEntry,
+    },
+    room::{
+        mode::{Credentials, GameKind, GameMode, LogEntry, ModeRequest, ModeResponse, Summary},
+        model::{RoomState, SpectatorId, TieBreak},
+        runtime::Runtime,
+    },
 };
-use futures::Stream;
 use futures::StreamExt;
 use paho_mqtt as mqtt;
-use paho_mqtt::Error as MqttError;
 use thiserror::Error;
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant},
+};
 use tracing::Instrument;
 use tracing::{debug, error, info, warn};
 
 pub mod dto;
+pub mod registry;
+pub mod transport;
+
+use registry::{RoomCtrl, RoomRegistry};
+use transport::{Packet, PacketStream, PahoTransport, Transport, TransportError};
 
 type Result<T> = std::result::Result<T, RoomCreationError>;
-type Topic = String;
+pub(crate) type Topic = String;
 
 #[derive(Error, Debug)]
 pub enum RoomCreationError {
     #[error("error: {0}")]
     UnknownError(String),
     #[error("{0}")]
-    MqttConnectionError(#[from] MqttError),
+    TransportError(#[from] TransportError),
     #[error("could not encode the message {0}")]
     MsgEncodingError(#[from] serde_json::Error),
     #[error("connection was reset")]
     ConnectionReset,
+    #[error("unknown question pack {0}")]
+    UnknownPack(String),
+    #[error("there have to be at least 2 teams and no more than players")]
+    InvalidTeams,
+    #[error("sudden death needs question packs to ask from")]
+    NoSuddenDeathQuestions,
+}
+
+#[derive(Error, Debug)]
+pub enum JoinRoomError {
+    #[error("invalid room id")]
+    InvalidRoomId,
+    #[error("room does not exist or the password is wrong")]
+    RoomNotFound,
+    #[error("room is full")]
+    RoomFull,
+    #[error("banned from the room")]
+    Banned,
+    #[error("error: {0}")]
+    UnknownError(String),
 }
 
 #[derive(Error, Debug)]
@@ -41,22 +73,26 @@ pub enum RuntimeError {
 }
 
 #[allow(dead_code)]
-pub(crate) enum Command {
+pub(crate) enum Command<R> {
     Skip,
     Abort(Option<String>),
-    Response(message::Response),
+    Response(R),
+    Responses(Vec<R>),
 }
 
 struct RoomData {
-    pub entry: RoomEntry,
-    pub players_limit: usize,
-    pub rounds_limit: usize,
+    pub id: repository::EntryId,
     id_as_base64: String,
 }
 
 impl RoomData {
+    pub fn new(id: repository::EntryId) -> Self {
+        let id_as_base64 = encode_room_id(&id);
+        Self { id, id_as_base64 }
+    }
+
     pub(super) fn internal_id(&self) -> InternalRoomId {
-        InternalRoomId::new(self.entry.id.clone(), self.id_as_base64.clone())
+        InternalRoomId::new(self.id, self.id_as_base64.clone())
     }
 }
 
@@ -66,17 +102,7 @@ impl std::fmt::Display for RoomData {
     }
 }
 
-impl Into<Room> for RoomData {
-    fn into(self) -> Room {
-        Room::new(
-            self.entry.id,
-            self.entry.password,
-            self.players_limit,
-            self.rounds_limit,
-        )
-    }
-}
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct InternalRoomId {
     pub id: repository::EntryId,
     as_base64: String,
@@ -96,16 +122,65 @@ impl std::fmt::Display for InternalRoomId {
 
 static ROOM_CHANNEL_PREFIX: &str = "rooms";
 
-#[tracing::instrument(skip(rep))]
+/// Topic on which `user` receives messages from the room's runtime.
+pub(crate) fn read_topic(room_id: &str, user: &str) -> Topic {
+    format!("{}/{}/{}/read", ROOM_CHANNEL_PREFIX, room_id, user)
+}
+
+/// Topic on which the room's runtime broadcasts to every player.
+pub(crate) fn room_topic(room_id: &str) -> Topic {
+    read_topic(room_id, "rt")
+}
+
+/// Topic on which `user` sends messages to the room's runtime.
+pub(crate) fn write_topic(room_id: &str, user: &str) -> Topic {
+    format!("{}/{}/{}/write", ROOM_CHANNEL_PREFIX, room_id, user)
+}
+
+/// Spectators are told apart from players by the prefix of their user.
+pub(crate) fn spectator_user(id: SpectatorId) -> String {
+    format!("s{}", id)
+}
+
+pub(crate) fn spectator_from_user(user: &str) -> Option<SpectatorId> {
+    user.strip_prefix('s')?.parse().ok()
+}
+
+/// Topic of the `user`'s last will, the broker publishes on it
+/// when the user's connection drops.
+pub(crate) fn will_topic(room_id: &str, user: &str) -> Topic {
+    format!("{}/{}/{}/will", ROOM_CHANNEL_PREFIX, room_id, user)
+}
+
+fn is_will_topic(topic: &str) -> bool {
+    topic.ends_with("/will")
+}
+
+#[tracing::instrument(skip(rep, registry, packs))]
 pub async fn create_new_room(
     mut rep: RepReqChannel,
     config: Config,
+    registry: RoomRegistry,
+    packs: PackLibrary,
     room_req: dto::NewRoomReq,
 ) -> Result<dto::NewRoomResp> {
+    if let Some(id) = packs.find_missing(&room_req.packs) {
+        return Err(RoomCreationError::UnknownPack(id.to_owned()));
+    }
+    let teams = room_req.teams;
+    if teams != 0 && !(2..=room_req.players_limit).contains(&teams) {
+        return Err(RoomCreationError::InvalidTeams);
+    }
+    if room_req.tie_break == TieBreak::SuddenDeath && room_req.packs.is_empty() {
+        return Err(RoomCreationError::NoSuddenDeathQuestions);
+    }
+    let (host_token, token_key) = (rand::random(), rand::random());
     let re = DataRepository::send_req(
         &mut rep,
         RepReq::CreateRoom {
             players_limit: room_req.players_limit,
+            host_token,
+            token_key,
         },
     )
     .await;
@@ -118,18 +193,37 @@ pub async fn create_new_room(
         }
     };
     let room_id = re.id;
-    let id_as_base64 = base64::encode(&re.id);
-    let resp = dto::NewRoomResp{
-        id: id_as_base64.clone(),
+    let rt_user =
+        match DataRepository::send_req(&mut rep, RepReq::CreateRuntimeUser { room_id }).await {
+            Ok(RepResp::UserCreated(val)) => val,
+            _ => {
+                let _ = DataRepository::send_req(&mut rep, RepReq::RemoveRoom { room_id }).await;
+                return Err(RoomCreationError::UnknownError(
+                    "couldn't create runtime's mqtt user".to_owned(),
+                ));
+            }
+        };
+    let rd = RoomData::new(re.id);
+    let resp = dto::NewRoomResp {
+        id: rd.id_as_base64.clone(),
         password: re.password,
+        host_token,
     };
-    let rd = RoomData {
-        entry: re,
-        players_limit: room_req.players_limit,
-        rounds_limit: room_req.rounds_limit,
-        id_as_base64,
+    let started = match room_req.mode {
+        GameKind::QuestionsAndAnswers => {
+            // knowing the seed tells which answer is whose,
+            // so players never get to choose it
+            let seed = config.runtime.seed.unwrap_or_else(rand::random);
+            let credentials = Credentials {
+                password: re.password,
+                host_token: resp.host_token,
+                token_key,
+            };
+            let state = Runtime::setup(re.id, &credentials, seed, &room_req, &packs);
+            start_room_rt::<Runtime>(rd, state, rt_user, config, rep.clone(), registry).await
+        }
     };
-    if let Err(err) = start_room_rt(rd, config).await {
+    if let Err(err) = started {
         // todo: some error handling?
         // for now we don't care
         let _ = DataRepository::send_req(&mut rep, RepReq::RemoveRoom { room_id }).await;
@@ -138,145 +232,623 @@ pub async fn create_new_room(
     Ok(resp)
 }
 
-#[tracing::instrument(skip(rd, config))]
-async fn start_room_rt(rd: RoomData, config: Config) -> Result<()> {
-    let mut cli = get_mqtt_client(&rd.id_as_base64, &config).await?;
-    let msg_stream = cli.get_stream(25); // arbitrarily chosen
-    connect_to_mqtt(&mut cli, &rd.id_as_base64).await?;
-    subscribe_default(&mut cli, &rd.id_as_base64, rd.players_limit).await?;
-    send_rt_start_msg(&mut cli, &rd.id_as_base64).await?;
+/// Restarts runtimes of the rooms that were still being played
+/// when the server went down. Returns the number of resumed rooms.
+#[tracing::instrument(skip(rep, config, registry))]
+pub async fn resume_rooms(
+    mut rep: RepReqChannel,
+    config: Config,
+    registry: RoomRegistry,
+) -> Result<usize> {
+    let rooms = match DataRepository::send_req(&mut rep, RepReq::LoadRooms).await {
+        Ok(RepResp::RoomsLoaded(rooms)) => rooms,
+        _ => {
+            return Err(RoomCreationError::UnknownError(
+                "couldn't load rooms from room repository".to_owned(),
+            ))
+        }
+    };
+    let mut resumed = 0;
+    for room in rooms {
+        let room_id = room.id;
+        let rd = RoomData::new(room.id);
+        info!("Resuming {}", rd);
+        let re = DataRepository::send_req(&mut rep, RepReq::CreateRuntimeUser { room_id }).await;
+        let rt_user = match re {
+            Ok(RepResp::UserCreated(val)) => val,
+            _ => {
+                error!("couldn't create runtime's mqtt user for {}", rd);
+                continue;
+            }
+        };
+        // The room stays in the repository on failure,
+        // it will be picked up again on the next start.
+        let (config, rep, registry) = (config.clone(), rep.clone(), registry.clone());
+        let started = match room.mode {
+            GameKind::QuestionsAndAnswers => {
+                resume_room_rt::<Runtime>(rd, room, rt_user, config, rep, registry).await
+            }
+        };
+        match started {
+            Ok(()) => resumed += 1,
+            Err(err) => error!("couldn't resume the room: {}", err),
+        }
+    }
+    Ok(resumed)
+}
+
+/// Resumes the room from its snapshot or, if the snapshot
+/// cannot be read, from whatever got logged in the room.
+async fn resume_room_rt<G: GameMode>(
+    rd: RoomData,
+    room: SavedRoom,
+    rt_user: UserEntry,
+    config: Config,
+    mut rep: RepReqChannel,
+    registry: RoomRegistry,
+) -> Result<()> {
+    let state = match serde_json::from_str::<G::State>(&room.snapshot) {
+        Ok(state) => state,
+        Err(err) => {
+            warn!("couldn't decode the snapshot of {}: {}", rd, err);
+            rebuild_room::<G>(&mut rep, rd.id, room.credentials.as_ref())
+                .await
+                .ok_or(err)?
+        }
+    };
+    start_room_rt::<G>(rd, state, rt_user, config, rep, registry).await
+}
+
+/// Replays the room's log, `None` if it cannot be loaded or decoded
+/// or there are no credentials to give back to the room.
+#[tracing::instrument(skip(rep, credentials))]
+async fn rebuild_room<G: GameMode>(
+    rep: &mut RepReqChannel,
+    room_id: repository::EntryId,
+    credentials: Option<&Credentials>,
+) -> Option<G::State> {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            error!("the room has no credentials to rebuild it with");
+            return None;
+        }
+    };
+    let entries = match DataRepository::send_req(rep, RepReq::LoadEvents { room_id }).await {
+        Ok(RepResp::EventsLoaded(entries)) => entries,
+        _ => {
+            error!("couldn't load the room's events");
+            return None;
+        }
+    };
+    info!("replaying {} events", entries.len());
+    match decode_events::<G>(&entries) {
+        Ok(events) => G::replay(events, credentials),
+        Err(err) => {
+            error!("couldn't decode the room's events: {}", err);
+            None
+        }
+    }
+}
+
+fn decode_events<G: GameMode>(
+    entries: &[LogEntry<String>],
+) -> std::result::Result<Vec<G::Event>, serde_json::Error> {
+    entries
+        .iter()
+        .map(|entry| serde_json::from_str(&entry.event))
+        .collect()
+}
+
+/// Players banned from the room are turned away
+/// if they come back with the token they were given.
+#[tracing::instrument(skip(rep, config))]
+pub async fn join_room(
+    mut rep: RepReqChannel,
+    config: Config,
+    player_req: dto::NewPlayerReq,
+) -> std::result::Result<dto::NewPlayerResp, JoinRoomError> {
+    let room_id = decode_room_id(&player_req.id).ok_or(JoinRoomError::InvalidRoomId)?;
+    let re = DataRepository::send_req(
+        &mut rep,
+        RepReq::AddPlayer {
+            room_id,
+            password: player_req.password,
+            token: player_req.token,
+        },
+    )
+    .await;
+    let (player_id, token) = match re {
+        Ok(RepResp::PlayerAdded(id, token)) => (id, token),
+        Err(RepError::RoomNotFound) => return Err(JoinRoomError::RoomNotFound),
+        Err(RepError::RoomFull) => return Err(JoinRoomError::RoomFull),
+        Err(RepError::Banned) => return Err(JoinRoomError::Banned),
+        _ => {
+            return Err(JoinRoomError::UnknownError(
+                "couldn't add a player in room repository".to_owned(),
+            ))
+        }
+    };
+    let re =
+        DataRepository::send_req(&mut rep, RepReq::CreatePlayerUser { room_id, player_id }).await;
+    let user = match re {
+        Ok(RepResp::UserCreated(val)) => val,
+        _ => {
+            // nobody is going to use the slot otherwise
+            let re =
+                DataRepository::send_req(&mut rep, RepReq::ReleasePlayerSlot { room_id }).await;
+            if let Err(err) = re {
+                error!("couldn't release the player's slot: {:?}", err);
+            }
+            return Err(JoinRoomError::UnknownError(
+                "couldn't create player's mqtt user".to_owned(),
+            ));
+        }
+    };
+    info!("{} joined as player {}", player_req.name, player_id);
+    let player = player_id.to_string();
+    Ok(dto::NewPlayerResp {
+        player_id,
+        token,
+        mqtt_host: config.mqtt.host,
+        username: user.username,
+        password: user.password,
+        read_topic: read_topic(&player_req.id, &player),
+        write_topic: write_topic(&player_req.id, &player),
+        room_topic: room_topic(&player_req.id),
+        will_topic: will_topic(&player_req.id, &player),
+    })
+}
+
+/// Gives out credentials that let the spectator watch the room
+/// and take part in the audience vote. Full rooms and players
+/// banned from the room are turned away like when joining it.
+#[tracing::instrument(skip(rep, config))]
+pub async fn spectate_room(
+    mut rep: RepReqChannel,
+    config: Config,
+    spectator_req: dto::NewSpectatorReq,
+) -> std::result::Result<dto::NewSpectatorResp, JoinRoomError> {
+    let room_id = decode_room_id(&spectator_req.id).ok_or(JoinRoomError::InvalidRoomId)?;
+    let re = DataRepository::send_req(
+        &mut rep,
+        RepReq::AddSpectator {
+            room_id,
+            password: spectator_req.password,
+            token: spectator_req.token,
+            limit: config.runtime.spectators_limit,
+        },
+    )
+    .await;
+    let spectator_id = match re {
+        Ok(RepResp::SpectatorAdded(val)) => val,
+        Err(RepError::RoomNotFound) => return Err(JoinRoomError::RoomNotFound),
+        Err(RepError::RoomFull) => return Err(JoinRoomError::RoomFull),
+        Err(RepError::Banned) => return Err(JoinRoomError::Banned),
+        _ => {
+            return Err(JoinRoomError::UnknownError(
+                "couldn't add a spectator in room repository".to_owned(),
+            ))
+        }
+    };
+    let re = DataRepository::send_req(
+        &mut rep,
+        RepReq::CreateSpectatorUser {
+            room_id,
+            spectator_id,
+        },
+    )
+    .await;
+    let user = match re {
+        Ok(RepResp::UserCreated(val)) => val,
+        _ => {
+            return Err(JoinRoomError::UnknownError(
+                "couldn't create spectator's mqtt user".to_owned(),
+            ))
+        }
+    };
+    info!("spectator {} joined", spectator_id);
+    Ok(dto::NewSpectatorResp {
+        spectator_id,
+        mqtt_host: config.mqtt.host,
+        username: user.username,
+        password: user.password,
+        room_topic: room_topic(&spectator_req.id),
+        vote_topic: write_topic(&spectator_req.id, &spectator_user(spectator_id)),
+    })
+}
+
+/// Room ids end up in mqtt topics and usernames so they are encoded
+/// with the url safe alphabet, which has no slashes or pluses.
+pub fn encode_room_id(id: &repository::EntryId) -> String {
+    base64::encode_config(id, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_room_id(id: &str) -> Option<repository::EntryId> {
+    let bytes = base64::decode_config(id, base64::URL_SAFE_NO_PAD).ok()?;
+    bytes.as_slice().try_into().ok()
+}
+
+#[tracing::instrument(skip(rd, state, rt_user, config, rep, registry))]
+async fn start_room_rt<G: GameMode>(
+    rd: RoomData,
+    state: G::State,
+    rt_user: UserEntry,
+    config: Config,
+    rep: RepReqChannel,
+    registry: RoomRegistry,
+) -> Result<()> {
+    let mut cli = get_mqtt_client(&rd.id_as_base64, &config)?;
+    let will = serde_json::to_string(&G::Response::runtime_lost())?;
+    connect_to_mqtt(&mut cli, &rd.id_as_base64, &rt_user, will).await?;
+    let game = G::from_state(state, config);
+    spawn_room_rt(cli, rd, game, rep, registry).await
+}
+
+/// Prepares the transport for the room and spawns its runtime.
+#[tracing::instrument(skip(cli, rd, game, rep, registry))]
+async fn spawn_room_rt<T: Transport, G: GameMode>(
+    mut cli: T,
+    rd: RoomData,
+    game: G,
+    rep: RepReqChannel,
+    registry: RoomRegistry,
+) -> Result<()> {
+    let msg_stream = cli.incoming().ok_or_else(|| {
+        RoomCreationError::UnknownError("transport's stream was already taken".to_owned())
+    })?;
+    subscribe_default(&mut cli, &rd.id_as_base64).await?;
+    send_rt_start_msg::<_, G::Response>(&mut cli, &rd.id_as_base64).await?;
     info!("spawning room rt");
-    tokio::spawn(create_room_rt_task(cli, Box::pin(msg_stream), rd, config).await);
+    let room_id = rd.internal_id();
+    let (ctrl_tx, ctrl_rx) = mpsc::channel(16); // arbitrarily chosen
+    let task = create_room_rt_task(cli, msg_stream, ctrl_rx, rd, game, rep, registry.clone());
+    registry.spawn(room_id, ctrl_tx, task).await;
     info!("spawned");
     Ok(())
 }
 
 #[tracing::instrument(skip(config))]
-async fn get_mqtt_client(room_id: &str, config: &Config) -> Result<mqtt::AsyncClient> {
+fn get_mqtt_client(room_id: &str, config: &Config) -> Result<PahoTransport> {
     let create_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(&config.mqtt.host)
         .client_id(format!("room-rt-{}", room_id))
         .mqtt_version(mqtt::MQTT_VERSION_5)
         .finalize();
-    let cli = mqtt::AsyncClient::new(create_opts)?;
+    let cli = PahoTransport::new(create_opts)?;
     Ok(cli)
 }
 
-#[tracing::instrument(skip(cli))]
-async fn connect_to_mqtt(cli: &mut mqtt::AsyncClient, room_id: &str) -> Result<()> {
+/// Connects the runtime's client, players learn about the runtime
+/// going away from `will` published on the room's topic.
+#[tracing::instrument(skip(cli, user, will))]
+async fn connect_to_mqtt(
+    cli: &mut PahoTransport,
+    room_id: &str,
+    user: &UserEntry,
+    will: String,
+) -> Result<()> {
     let lwt = mqtt::MessageBuilder::new()
-        .topic(format!("test/room/{}", room_id))
-        .payload(format!("Room rt {} lost connection", room_id))
+        .topic(room_topic(room_id))
+        .payload(will)
         .finalize();
     // todo: get duration from configuration
     let conn_opts = mqtt::ConnectOptionsBuilder::new()
         .keep_alive_interval(Duration::from_secs(20))
         .clean_session(true) // todo: maybe we should start with clean for the test msg
         .will_message(lwt)
+        .user_name(user.username.as_str())
+        .password(user.password.as_str())
         .finalize();
     cli.connect(conn_opts).await?;
     Ok(())
 }
 
 #[tracing::instrument(skip(cli))]
-async fn subscribe_default(
-    cli: &mut mqtt::AsyncClient,
-    room_id: &str,
-    players_limit: usize,
-) -> Result<()> {
-    let mut channels = vec![format!("{}/{}/rt/write", ROOM_CHANNEL_PREFIX, room_id)];
-    for i in 0..players_limit {
-        channels.push(format!("{}/{}/{}/write", ROOM_CHANNEL_PREFIX, room_id, i));
-    }
-    let qos: Vec<i32> = vec![0; channels.len()];
-    match cli.subscribe_many(&channels, &qos).await {
-        Ok(qosv) => debug!("QoS granted: {:?}", qosv),
-        Err(e) => {
-            error!("Error subscribing to topics {:?}", e);
-            debug!("Disconnecting");
-            cli.disconnect(None).await?;
-            return Err(RoomCreationError::from(e));
-        }
+async fn subscribe_default<T: Transport>(cli: &mut T, room_id: &str) -> Result<()> {
+    let channels = default_topics(room_id);
+    if let Err(e) = cli.subscribe(&channels).await {
+        error!("Error subscribing to topics {:?}", e);
+        debug!("Disconnecting");
+        cli.disconnect().await?;
+        return Err(RoomCreationError::from(e));
     }
     Ok(())
 }
 
-#[tracing::instrument(skip(cli, msg_stream, rd, config))]
-async fn create_room_rt_task<S>(
-    mut cli: mqtt::AsyncClient,
-    mut msg_stream: Pin<Box<S>>,
+/// Topics the runtime listens on. Players are matched with a wildcard
+/// as their ids are not known upfront, kicked players free their slots.
+fn default_topics(room_id: &str) -> Vec<Topic> {
+    vec![write_topic(room_id, "+"), will_topic(room_id, "+")]
+}
+
+#[tracing::instrument(skip(cli, msg_stream, ctrl, rd, game, rep, registry))]
+fn create_room_rt_task<T: Transport, G: GameMode>(
+    mut cli: T,
+    mut msg_stream: PacketStream,
+    mut ctrl: mpsc::Receiver<RoomCtrl>,
     rd: RoomData,
-    config: Config,
-) -> impl std::future::Future<Output = ()>
-where
-    S: Stream<Item = Option<mqtt::Message>>,
-{
+    mut game: G,
+    mut rep: RepReqChannel,
+    registry: RoomRegistry,
+) -> impl std::future::Future<Output = ()> {
     info!("Inside a room creation task");
     let span = tracing::debug_span!("room message handling", room_id = rd.id_as_base64.as_str());
     async move {
         let room_id = rd.internal_id();
         info!("Created new room");
         debug!("Waiting for messages");
-        let runtime = room::runtime::Runtime::new(rd.into(), config.clone());
-        info!("Runtime created");
-        while let Some(msg) = msg_stream.next().await {
-            debug!("Got msg");
-            let msg = parse_msg(msg);
-            match msg {
-                Ok((topic, msg)) => {
-                    let resp = runtime.process_msg(&player_from_topic(&topic), msg).await;
-                    if handle_resp(&mut cli, &room_id, topic, resp).await {
+        save_room(&mut rep, &rd, &game).await;
+        append_log(&mut rep, &rd, &mut game).await;
+        // rooms are kept only if the server goes down so they can be resumed
+        let mut keep_room = false;
+        loop {
+            tokio::select! {
+                msg = msg_stream.next() => match msg {
+                    Some(msg) => {
+                        let close = handle_msg(&mut cli, &room_id, &mut game, msg).await;
+                        persist(&mut rep, &rd, &mut game).await;
+                        if close {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                _ = wait_until(game.wakeup()) => {
+                    let close = handle_timer(&mut cli, &room_id, &mut game).await;
+                    persist(&mut rep, &rd, &mut game).await;
+                    if close {
                         break;
                     }
                 }
-                Err(RuntimeError::ConnectionReset) => {
-                    if cli.is_connected() || !try_reconnect(&mut cli).await {
-                        warn!("channel died");
-                        info!("aborting...");
+                ctrl_msg = ctrl.recv() => match ctrl_msg {
+                    Some(RoomCtrl::Status(tx)) => {
+                        let _ = tx.send(room_status::<G>(&rd, game.summary()));
+                    }
+                    Some(RoomCtrl::ServerShutdown) => {
+                        info!("Server is shutting down");
+                        let resp = G::Response::server_shutting_down();
+                        dispatch_resp(&mut cli, &room_id, resp).await;
+                        keep_room = true;
                         break;
                     }
-                }
-                Err(RuntimeError::MsgDecodingError(inner)) => {
-                    // We don't know who send it so yeah
-                    // just skip
-                    error!("{}", inner);
-                }
+                    Some(RoomCtrl::Shutdown) | None => {
+                        info!("Room was asked to shut down");
+                        break;
+                    }
+                },
             }
         }
+        disconnect(&mut cli, &room_id).await;
+        if keep_room {
+            save_room(&mut rep, &rd, &game).await;
+        } else {
+            remove_room(&mut rep, &room_id).await;
+        }
+        registry.unregister(&room_id).await;
     }
     .instrument(span)
 }
 
 #[tracing::instrument(skip(cli))]
-async fn send_rt_start_msg(cli: &mut mqtt::AsyncClient, room_id: &str) -> Result<()> {
-    let msg = mqtt::MessageBuilder::new()
-        .topic(format!("{}/{}/rt/read", ROOM_CHANNEL_PREFIX, room_id))
-        .payload(serde_json::to_string(&message::Response::RuntimeStarted).unwrap())
-        .qos(0)
-        .finalize();
-    cli.publish(msg).await?;
+async fn disconnect<T: Transport>(cli: &mut T, room_id: &InternalRoomId) {
+    if !cli.is_connected() {
+        return;
+    }
+    let topics = default_topics(&room_id.as_base64);
+    if let Err(err) = cli.unsubscribe(&topics).await {
+        error!("could not unsubscribe from topics: {}", err);
+    }
+    info!("Disconnecting");
+    if let Err(err) = cli.disconnect().await {
+        error!("could not disconnect from mqtt: {}", err);
+    }
+}
+
+/// Returns true if the room should be closed.
+async fn handle_msg<T: Transport, G: GameMode>(
+    cli: &mut T,
+    room_id: &InternalRoomId,
+    game: &mut G,
+    msg: Option<Packet>,
+) -> bool {
+    debug!("Got msg");
+    match parse_msg::<G::Request>(msg) {
+        Ok((topic, msg)) => {
+            let resp = game.process_msg(&player_from_topic(&topic), msg);
+            if handle_resp(cli, room_id, topic, resp).await {
+                return true;
+            }
+            if game.is_finished() {
+                info!("game is over, closing the room");
+                return true;
+            }
+            false
+        }
+        Err(RuntimeError::ConnectionReset) => {
+            if cli.is_connected() || !try_reconnect(cli).await {
+                warn!("channel died");
+                info!("aborting...");
+                return true;
+            }
+            false
+        }
+        Err(RuntimeError::MsgDecodingError(inner)) => {
+            // We don't know who send it so yeah
+            // just skip
+            error!("{}", inner);
+            false
+        }
+    }
+}
+
+async fn wait_until(at: Option<Instant>) {
+    match at {
+        Some(at) => time::delay_until(at).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Returns true if the room should be closed.
+async fn handle_timer<T: Transport, G: GameMode>(
+    cli: &mut T,
+    room_id: &InternalRoomId,
+    game: &mut G,
+) -> bool {
+    let resp = game.process_timer(Instant::now());
+    let topic = room_topic(&room_id.as_base64);
+    if handle_resp(cli, room_id, topic, resp).await {
+        return true;
+    }
+    if game.is_finished() {
+        info!("game is over, closing the room");
+        return true;
+    }
+    false
+}
+
+fn room_status<G: GameMode>(rd: &RoomData, summary: Summary) -> dto::RoomStatus {
+    dto::RoomStatus {
+        id: rd.id_as_base64.clone(),
+        mode: G::KIND,
+        state: summary.state,
+        players: summary.players,
+        players_limit: summary.players_limit,
+        rounds_limit: summary.rounds_limit,
+        round: summary.round,
+    }
+}
+
+/// Saves the room if it has changed and appends whatever got logged.
+/// The snapshot goes first, a log missing its last events is easier
+/// to live with than events logged twice under the same numbers.
+/// Players thrown out of the room lose their mqtt users.
+async fn persist<G: GameMode>(rep: &mut RepReqChannel, rd: &RoomData, game: &mut G) {
+    if game.take_changed() {
+        save_room(rep, rd, game).await;
+    }
+    append_log(rep, rd, game).await;
+    evict_players(rep, rd, game).await;
+}
+
+#[tracing::instrument(skip(rep, rd, game), fields(room = %rd))]
+async fn evict_players<G: GameMode>(rep: &mut RepReqChannel, rd: &RoomData, game: &mut G) {
+    for eviction in game.take_evicted() {
+        let req = RepReq::EvictPlayer {
+            room_id: rd.id,
+            player_id: eviction.player,
+            ban: eviction.banned,
+        };
+        match DataRepository::send_req(rep, req).await {
+            Ok(RepResp::PlayerEvicted) => debug!("Player {} evicted", eviction.player),
+            Ok(_) => error!("Got unexpected response while evicting a player"),
+            Err(err) => error!("Could not evict player {}: {:?}", eviction.player, err),
+        }
+    }
+}
+
+#[tracing::instrument(skip(rep, rd, game), fields(room = %rd))]
+async fn append_log<G: GameMode>(rep: &mut RepReqChannel, rd: &RoomData, game: &mut G) {
+    let mut events = Vec::new();
+    for entry in game.take_log() {
+        match serde_json::to_string(&entry.event) {
+            Ok(event) => events.push(LogEntry {
+                seq: entry.seq,
+                state: entry.state,
+                event,
+            }),
+            Err(err) => error!("Could not encode event {}: {}", entry.seq, err),
+        }
+    }
+    if events.is_empty() {
+        return;
+    }
+    let req = RepReq::AppendEvents {
+        room_id: rd.id,
+        events,
+    };
+    match DataRepository::send_req(rep, req).await {
+        Ok(RepResp::EventsAppended) => debug!("Events appended"),
+        Ok(_) => error!("Got unexpected response while appending events"),
+        Err(err) => error!("Could not append events: {:?}", err),
+    }
+}
+
+#[tracing::instrument(skip(rep, rd, game), fields(room = %rd))]
+async fn save_room<G: GameMode>(rep: &mut RepReqChannel, rd: &RoomData, game: &G) {
+    let snapshot = match serde_json::to_string(game.state()) {
+        Ok(val) => val,
+        Err(err) => {
+            error!("Could not encode the room: {}", err);
+            return;
+        }
+    };
+    let summary = game.summary();
+    // player ids are given out one after another, every player
+    // who left the room took one of them without holding a slot anymore
+    let players_limit = match summary.state {
+        RoomState::AcceptingPlayers => summary.players_limit + summary.slots_freed,
+        _ => 0,
+    };
+    let req = RepReq::SaveRoom {
+        room_id: rd.id,
+        mode: G::KIND,
+        state: summary.state,
+        players_limit,
+        snapshot,
+    };
+    match DataRepository::send_req(rep, req).await {
+        Ok(RepResp::RoomSaved) => debug!("Room saved"),
+        Ok(_) => error!("Got unexpected response while saving the room"),
+        Err(err) => error!("Could not save the room: {:?}", err),
+    }
+}
+
+#[tracing::instrument(skip(rep))]
+async fn remove_room(rep: &mut RepReqChannel, room_id: &InternalRoomId) {
+    let re = DataRepository::send_req(
+        rep,
+        RepReq::RemoveRoom {
+            room_id: room_id.id,
+        },
+    )
+    .await;
+    match re {
+        Ok(RepResp::RoomRemoved) => info!("Room removed"),
+        Ok(_) => error!("Got unexpected response while removing the room"),
+        Err(err) => error!("Could not remove the room: {:?}", err),
+    }
+}
+
+#[tracing::instrument(skip(cli))]
+async fn send_rt_start_msg<T: Transport, R: ModeResponse>(
+    cli: &mut T,
+    room_id: &str,
+) -> Result<()> {
+    let packet = Packet {
+        topic: room_topic(room_id),
+        payload: serde_json::to_string(&R::runtime_started())?,
+    };
+    cli.publish(packet).await?;
     Ok(())
 }
 
 #[tracing::instrument(skip(msg))]
-fn parse_msg(
-    msg: Option<mqtt::Message>,
-) -> std::result::Result<(Topic, message::Request), RuntimeError> {
+fn parse_msg<R: ModeRequest>(msg: Option<Packet>) -> std::result::Result<(Topic, R), RuntimeError> {
     match msg {
-        Some(val) => Ok((
-            val.topic().into(),
-            serde_json::from_str(val.payload_str().as_ref())?,
-        )),
+        // whatever the payload of the last will is, it means the player is gone
+        Some(val) if is_will_topic(&val.topic) => Ok((val.topic, R::connection_lost())),
+        Some(val) => Ok((val.topic, serde_json::from_str(&val.payload)?)),
         None => Err(RuntimeError::ConnectionReset),
     }
 }
 
 #[tracing::instrument(skip(cli, rd_id, cmd))]
-async fn handle_resp(
-    cli: &mut mqtt::AsyncClient,
+async fn handle_resp<T: Transport, R: ModeResponse>(
+    cli: &mut T,
     rd_id: &InternalRoomId,
     src_topic: Topic,
-    cmd: Command,
+    cmd: Command<R>,
 ) -> bool {
     match cmd {
         Command::Abort(msg) => {
@@ -285,30 +857,41 @@ async fn handle_resp(
             } else {
                 error!("Aborting...");
             }
-            if cli.is_connected() {
-                info!("Disconnecting");
-                // todo: unsubscribe from topics here
-                cli.disconnect(None).await.unwrap();
-            }
             true
         }
-        Command::Response(message::Response::Priv(player, resp)) => {
-            send_resp(&player.to_string(), resp.as_ref(), cli, &rd_id.as_base64).await;
+        Command::Response(resp) => {
+            dispatch_resp(cli, rd_id, resp).await;
             false
         }
-        Command::Response(resp) => {
-            send_resp("rt", &resp, cli, &rd_id.as_base64).await;
+        Command::Responses(resps) => {
+            for resp in resps {
+                dispatch_resp(cli, rd_id, resp).await;
+            }
             false
         }
-        _ => false,
+        Command::Skip => false,
     }
 }
 
+#[tracing::instrument(skip(cli, rd_id))]
+async fn dispatch_resp<T: Transport, R: ModeResponse>(
+    cli: &mut T,
+    rd_id: &InternalRoomId,
+    resp: R,
+) {
+    let (to, resp) = resp.route();
+    let topic = match to {
+        Some(player) => read_topic(&rd_id.as_base64, &player.to_string()),
+        None => room_topic(&rd_id.as_base64),
+    };
+    send_resp(topic, &resp, cli).await
+}
+
 const CONN_RETRIES: u32 = 12;
 const RETRY_WAIT_MS: u64 = 5000;
 
 #[tracing::instrument(skip(cli))]
-async fn try_reconnect(cli: &mut mqtt::AsyncClient) -> bool {
+async fn try_reconnect<T: Transport>(cli: &mut T) -> bool {
     warn!("Connection lost trying to reconnect");
     for _ in 0..CONN_RETRIES {
         if cli.reconnect().await.is_ok() {
@@ -322,16 +905,520 @@ async fn try_reconnect(cli: &mut mqtt::AsyncClient) -> bool {
 }
 
 #[tracing::instrument(skip(cli))]
-async fn send_resp(to: &str, resp: &message::Response, cli: &mut mqtt::AsyncClient, rd_id: &str) {
-    let msg = mqtt::MessageBuilder::new()
-        .topic(format!("{}/{}/rt/read", ROOM_CHANNEL_PREFIX, rd_id))
-        .payload(serde_json::to_string(&resp).unwrap())
-        .qos(0)
-        .finalize();
-    cli.publish(msg).await.unwrap();
+async fn send_resp<T: Transport, R: ModeResponse>(topic: Topic, resp: &R, cli: &mut T) {
+    let packet = Packet {
+        topic,
+        payload: serde_json::to_string(&resp).unwrap(),
+    };
+    if let Err(err) = cli.publish(packet).await {
+        error!("could not send the response: {}", err);
+    }
 }
 
 fn player_from_topic(topic: &Topic) -> String {
-    let user = topic.split('/').nth(2).unwrap();
+    // topics look like rooms/<room_id>/<player>/write
+    let user = topic.rsplit('/').nth(1).unwrap_or_default();
     user.to_owned()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use crate::message::{ErrResponse, Request, Response};
+    use crate::room::mode::Eviction;
+    use crate::room::model::{player_token, Room};
+    use transport::{MemoryClient, MemoryTransport};
+
+    type RepRequests = mpsc::UnboundedReceiver<RepReq>;
+
+    /// Answers requests made by the room's runtime
+    /// and passes them on so tests can check them.
+    fn fake_repository() -> (RepReqChannel, RepRequests) {
+        let (tx, mut rx): (RepReqChannel, _) = mpsc::channel(16);
+        let (seen_tx, seen_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((req, mut resp_tx)) = rx.recv().await {
+                let resp = match req {
+                    RepReq::SaveRoom { .. } => Ok(RepResp::RoomSaved),
+                    RepReq::RemoveRoom { .. } => Ok(RepResp::RoomRemoved),
+                    RepReq::AppendEvents { .. } => Ok(RepResp::EventsAppended),
+                    RepReq::AddPlayer { .. } => Ok(RepResp::PlayerAdded(0, 0)),
+                    RepReq::AddSpectator { .. } => Err(RepError::RoomFull),
+                    RepReq::ReleasePlayerSlot { .. } => Ok(RepResp::PlayerSlotReleased),
+                    RepReq::EvictPlayer { .. } => Ok(RepResp::PlayerEvicted),
+                    _ => Err(RepError::InternalError("unexpected request".into())),
+                };
+                let _ = seen_tx.send(req);
+                let _ = resp_tx.send(resp).await;
+            }
+        });
+        (tx, seen_rx)
+    }
+
+    async fn start_room(
+        players_limit: usize,
+        rounds_limit: usize,
+    ) -> (MemoryClient, RoomRegistry, RepRequests, String) {
+        let (transport, client) = MemoryTransport::pair();
+        let (rep, reqs) = fake_repository();
+        let registry = RoomRegistry::new();
+        let rd = RoomData::new([1; 12]);
+        let room_id = rd.id_as_base64.clone();
+        let room = Room::new(rd.id, 0, players_limit, rounds_limit);
+        let game = Runtime::new(room, test_config());
+        spawn_room_rt(transport, rd, game, rep, registry.clone())
+            .await
+            .unwrap();
+        (client, registry, reqs, room_id)
+    }
+
+    fn send(client: &MemoryClient, room_id: &str, player: &str, req: Request) {
+        let payload = serde_json::to_string(&req).unwrap();
+        client.publish(write_topic(room_id, player), payload);
+    }
+
+    async fn recv(client: &mut MemoryClient) -> Response {
+        let packet = client.recv().await.expect("transport was closed");
+        serde_json::from_str(&packet.payload).unwrap()
+    }
+
+    /// Waits for the room to drop its transport
+    /// and returns the last request it made to the repository.
+    async fn wait_for_close(client: &mut MemoryClient, reqs: &mut RepRequests) -> RepReq {
+        while client.recv().await.is_some() {}
+        let mut last = None;
+        while let Ok(req) = reqs.try_recv() {
+            last = Some(req);
+        }
+        last.expect("room made no requests")
+    }
+
+    #[tokio::test]
+    async fn spectators_are_limited_by_the_config() {
+        let (rep, mut reqs) = fake_repository();
+        let spectator_req = dto::NewSpectatorReq {
+            id: encode_room_id(&[1; 12]),
+            password: 0,
+            token: Some(5),
+        };
+        let spectated = spectate_room(rep, test_config(), spectator_req).await;
+        assert!(matches!(spectated, Err(JoinRoomError::RoomFull)));
+        assert!(matches!(
+            reqs.recv().await,
+            Some(RepReq::AddSpectator {
+                token: Some(5),
+                limit: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn room_ids_fit_in_topics() {
+        // the standard alphabet would encode these with slashes
+        let id = [0xff; 12];
+        let encoded = encode_room_id(&id);
+        assert!(!encoded.contains('/') && !encoded.contains('+'));
+        assert_eq!(decode_room_id(&encoded), Some(id));
+        let topic = write_topic(&encoded, "3");
+        assert_eq!(player_from_topic(&topic), "3");
+    }
+
+    #[tokio::test]
+    async fn rooms_with_invalid_teams_are_not_created() {
+        for teams in &[1, 5] {
+            let (rep, mut reqs) = fake_repository();
+            let room_req = serde_json::from_value(serde_json::json!({
+                "players_limit": 4,
+                "rounds_limit": 1,
+                "teams": teams,
+            }))
+            .unwrap();
+            let packs = PackLibrary::new(Vec::new()).unwrap();
+            let created =
+                create_new_room(rep, test_config(), RoomRegistry::new(), packs, room_req).await;
+            assert!(matches!(created, Err(RoomCreationError::InvalidTeams)));
+            assert!(reqs.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn sudden_death_is_not_played_without_packs() {
+        let (rep, mut reqs) = fake_repository();
+        let room_req = serde_json::from_value(serde_json::json!({
+            "players_limit": 2,
+            "rounds_limit": 1,
+            "tie_break": "SuddenDeath",
+        }))
+        .unwrap();
+        let packs = PackLibrary::new(Vec::new()).unwrap();
+        let created =
+            create_new_room(rep, test_config(), RoomRegistry::new(), packs, room_req).await;
+        assert!(matches!(
+            created,
+            Err(RoomCreationError::NoSuddenDeathQuestions)
+        ));
+        assert!(reqs.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn room_plays_a_game_over_memory_transport() {
+        let (mut client, registry, mut reqs, room_id) = start_room(1, 1).await;
+        assert_eq!(recv(&mut client).await, Response::RuntimeStarted);
+        let join = Request::JoinRoom {
+            name: "Alice".into(),
+            token: player_token(0, 0),
+        };
+        send(&client, &room_id, "0", join);
+        assert!(matches!(
+            recv(&mut client).await,
+            Response::NewPlayerJoined(..)
+        ));
+        let question = Request::AddQuestion {
+            content: "why?".into(),
+        };
+        send(&client, &room_id, "0", question);
+        assert!(matches!(
+            recv(&mut client).await,
+            Response::QuestionAdded { .. }
+        ));
+        assert!(matches!(recv(&mut client).await, Response::NewRound { .. }));
+        let answer = Request::AddAnswer {
+            content: "because".into(),
+        };
+        send(&client, &room_id, "0", answer);
+        assert!(matches!(
+            recv(&mut client).await,
+            Response::AnswerAdded { .. }
+        ));
+        assert!(matches!(
+            recv(&mut client).await,
+            Response::PollingStarted { .. }
+        ));
+        // the only player has nothing to vote for but their own answer
+        assert!(matches!(recv(&mut client).await, Response::GameScore(..)));
+        assert_eq!(recv(&mut client).await, Response::GameFinished);
+        let last = wait_for_close(&mut client, &mut reqs).await;
+        assert!(matches!(last, RepReq::RemoveRoom { .. }));
+        assert!(registry.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn private_responses_go_to_player_topic() {
+        let (mut client, _registry, _reqs, room_id) = start_room(2, 1).await;
+        let packet = client.recv().await.unwrap();
+        assert_eq!(packet.topic, room_topic(&room_id));
+        send(&client, &room_id, "1", Request::GetRoomState);
+        let packet = client.recv().await.unwrap();
+        assert_eq!(packet.topic, read_topic(&room_id, "1"));
+        let resp: Response = serde_json::from_str(&packet.payload).unwrap();
+        assert_eq!(resp, Response::Err(ErrResponse::NotJoined));
+    }
+
+    #[tokio::test]
+    async fn room_survives_lost_connection() {
+        let (mut client, registry, mut reqs, room_id) = start_room(1, 1).await;
+        assert_eq!(recv(&mut client).await, Response::RuntimeStarted);
+        // the runtime does not listen on read topics
+        let payload = serde_json::to_string(&Request::GetRoomState).unwrap();
+        client.publish(read_topic(&room_id, "0"), payload);
+        client.drop_connection();
+        send(&client, &room_id, "rt", Request::GetRoomState);
+        assert!(matches!(recv(&mut client).await, Response::RoomState(..)));
+        assert!(registry.shutdown(&room_id).await);
+        let last = wait_for_close(&mut client, &mut reqs).await;
+        assert!(matches!(last, RepReq::RemoveRoom { .. }));
+    }
+
+    #[tokio::test]
+    async fn last_will_disconnects_the_player() {
+        let (mut client, _registry, _reqs, room_id) = start_room(2, 1).await;
+        assert_eq!(recv(&mut client).await, Response::RuntimeStarted);
+        let join = Request::JoinRoom {
+            name: "Alice".into(),
+            token: player_token(0, 0),
+        };
+        send(&client, &room_id, "0", join);
+        assert!(matches!(
+            recv(&mut client).await,
+            Response::NewPlayerJoined(..)
+        ));
+        client.publish(will_topic(&room_id, "0"), "gone".into());
+        assert_eq!(
+            recv(&mut client).await,
+            Response::PlayerDisconnected { id: 0 }
+        );
+    }
+
+    #[tokio::test]
+    async fn kicked_players_lose_their_mqtt_users() {
+        let (transport, mut client) = MemoryTransport::pair();
+        let (rep, mut reqs) = fake_repository();
+        let rd = RoomData::new([3; 12]);
+        let room_id = rd.id_as_base64.clone();
+        let mut room = Room::new(rd.id, 0, 3, 1);
+        room.host_token = Some(7);
+        let game = Runtime::new(room, test_config());
+        spawn_room_rt(transport, rd, game, rep, RoomRegistry::new())
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut client).await, Response::RuntimeStarted);
+        for player in &["0", "1"] {
+            let join = Request::JoinRoom {
+                name: player.to_string(),
+                token: player_token(0, player.parse().unwrap()),
+            };
+            send(&client, &room_id, player, join);
+            recv(&mut client).await;
+        }
+        let cmd = crate::message::HostCommand::Ban { player: 1 };
+        send(&client, &room_id, "0", Request::Host { token: 7, cmd });
+        assert!(matches!(
+            recv(&mut client).await,
+            Response::PlayerKicked { id: 1, .. }
+        ));
+        loop {
+            match reqs.recv().await.expect("no eviction") {
+                RepReq::EvictPlayer { player_id, ban, .. } => {
+                    assert_eq!((player_id, ban), (1, true));
+                    break;
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn room_is_kept_when_server_shuts_down() {
+        let (mut client, registry, mut reqs, _) = start_room(2, 1).await;
+        assert_eq!(recv(&mut client).await, Response::RuntimeStarted);
+        registry.shutdown_all(Duration::from_secs(1)).await;
+        assert_eq!(recv(&mut client).await, Response::ServerShuttingDown);
+        let last = wait_for_close(&mut client, &mut reqs).await;
+        assert!(matches!(last, RepReq::SaveRoom { .. }));
+    }
+
+    #[tokio::test]
+    async fn slot_is_released_if_player_cannot_get_in() {
+        let (rep, mut reqs) = fake_repository();
+        let req = dto::NewPlayerReq {
+            id: encode_room_id(&[1; 12]),
+            password: 0,
+            name: "Alice".into(),
+            token: None,
+        };
+        // the fake repository cannot create mqtt users
+        let res = join_room(rep, test_config(), req).await;
+        assert!(matches!(res, Err(JoinRoomError::UnknownError(_))));
+        assert!(matches!(reqs.recv().await, Some(RepReq::AddPlayer { .. })));
+        assert!(matches!(
+            reqs.recv().await,
+            Some(RepReq::CreatePlayerUser { .. })
+        ));
+        assert!(matches!(
+            reqs.recv().await,
+            Some(RepReq::ReleasePlayerSlot { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn room_is_rebuilt_from_loaded_events() {
+        let credentials = Credentials {
+            password: 11,
+            host_token: 12,
+            token_key: 13,
+        };
+        let mut room = Room::new([4; 12], credentials.password, 1, 1);
+        room.seed = 3;
+        room.host_token = Some(credentials.host_token);
+        room.token_key = credentials.token_key;
+        let mut game = Runtime::new(room, test_config());
+        let token = player_token(credentials.token_key, 0);
+        let join = Request::JoinRoom {
+            name: "Alice".into(),
+            token,
+        };
+        game.process_msg("0", join);
+        let question = Request::AddQuestion {
+            content: "why?".into(),
+        };
+        game.process_msg("0", question);
+        // events come out of the repository the way they were appended
+        let entries: Vec<_> = game
+            .take_log()
+            .into_iter()
+            .map(|entry| LogEntry {
+                seq: entry.seq,
+                state: entry.state,
+                event: serde_json::to_string(&entry.event).unwrap(),
+            })
+            .collect();
+        assert!(entries
+            .iter()
+            .all(|entry| !entry.event.contains(&token.to_string())));
+        let (mut rep, mut rx): (RepReqChannel, _) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some((req, mut resp_tx)) = rx.recv().await {
+                let resp = match req {
+                    RepReq::LoadEvents { .. } => Ok(RepResp::EventsLoaded(entries.clone())),
+                    _ => Err(RepError::InternalError("unexpected request".into())),
+                };
+                let _ = resp_tx.send(resp).await;
+            }
+        });
+        assert!(rebuild_room::<Runtime>(&mut rep, [4; 12], None)
+            .await
+            .is_none());
+        let rebuilt = rebuild_room::<Runtime>(&mut rep, [4; 12], Some(&credentials))
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&rebuilt).unwrap(),
+            serde_json::to_value(game.state()).unwrap()
+        );
+        assert_eq!(rebuilt.state, RoomState::Playing);
+    }
+
+    /// Smallest possible game, everything players say is repeated
+    /// to the whole room until somebody says "bye".
+    struct Echo {
+        said: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum EchoResponse {
+        Started,
+        ShuttingDown,
+        Lost,
+        Said(String),
+    }
+
+    impl ModeResponse for EchoResponse {
+        fn runtime_started() -> Self {
+            EchoResponse::Started
+        }
+
+        fn server_shutting_down() -> Self {
+            EchoResponse::ShuttingDown
+        }
+
+        fn runtime_lost() -> Self {
+            EchoResponse::Lost
+        }
+
+        fn route(self) -> (Option<crate::room::model::PlayerId>, Self) {
+            (None, self)
+        }
+    }
+
+    impl ModeRequest for String {
+        fn connection_lost() -> Self {
+            "bye".into()
+        }
+    }
+
+    impl GameMode for Echo {
+        type State = Vec<String>;
+        type Request = String;
+        type Response = EchoResponse;
+        type Event = String;
+
+        const KIND: GameKind = GameKind::QuestionsAndAnswers;
+
+        fn setup(
+            _: repository::EntryId,
+            _: &Credentials,
+            _: u64,
+            _: &dto::NewRoomReq,
+            _: &PackLibrary,
+        ) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn from_state(said: Vec<String>, _: Config) -> Self {
+            Self { said }
+        }
+
+        fn replay(events: Vec<String>, _: &Credentials) -> Option<Vec<String>> {
+            Some(events)
+        }
+
+        fn state(&self) -> &Vec<String> {
+            &self.said
+        }
+
+        fn summary(&self) -> Summary {
+            let done = self.said.last().map(String::as_str) == Some("bye");
+            Summary {
+                state: if done {
+                    RoomState::Dead
+                } else {
+                    RoomState::Playing
+                },
+                players: 1,
+                players_limit: 1,
+                slots_freed: 0,
+                rounds_limit: 0,
+                round: None,
+            }
+        }
+
+        fn process_msg(&mut self, _: &str, msg: String) -> Command<EchoResponse> {
+            self.said.push(msg.clone());
+            Command::Response(EchoResponse::Said(msg))
+        }
+
+        fn process_timer(&mut self, _: Instant) -> Command<EchoResponse> {
+            Command::Skip
+        }
+
+        fn wakeup(&self) -> Option<Instant> {
+            None
+        }
+
+        fn take_changed(&mut self) -> bool {
+            true
+        }
+
+        fn take_log(&mut self) -> Vec<LogEntry<String>> {
+            Vec::new()
+        }
+
+        fn take_evicted(&mut self) -> Vec<Eviction> {
+            Vec::new()
+        }
+    }
+
+    async fn recv_echo(client: &mut MemoryClient) -> EchoResponse {
+        let packet = client.recv().await.expect("transport was closed");
+        serde_json::from_str(&packet.payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn room_loop_drives_any_game_mode() {
+        let (transport, mut client) = MemoryTransport::pair();
+        let (rep, mut reqs) = fake_repository();
+        let registry = RoomRegistry::new();
+        let rd = RoomData::new([2; 12]);
+        let room_id = rd.id_as_base64.clone();
+        let game = Echo::from_state(Vec::new(), test_config());
+        spawn_room_rt(transport, rd, game, rep, registry.clone())
+            .await
+            .unwrap();
+        assert_eq!(recv_echo(&mut client).await, EchoResponse::Started);
+        let topic = write_topic(&room_id, "0");
+        client.publish(topic.clone(), "\"hello\"".into());
+        assert_eq!(
+            recv_echo(&mut client).await,
+            EchoResponse::Said("hello".into())
+        );
+        client.publish(topic, "\"bye\"".into());
+        assert_eq!(
+            recv_echo(&mut client).await,
+            EchoResponse::Said("bye".into())
+        );
+        let last = wait_for_close(&mut client, &mut reqs).await;
+        assert!(matches!(last, RepReq::RemoveRoom { .. }));
+    }
+}
@@ -0,0 +1,226 @@
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{error, info, warn};
+
+use super::{decode_room_id, dto, InternalRoomId};
+
+/// Messages the server can send to a running room.
+#[derive(Debug)]
+pub(crate) enum RoomCtrl {
+    Status(oneshot::Sender<dto::RoomStatus>),
+    Shutdown,
+    ServerShutdown, // lets players know why the room is going away
+}
+
+pub(crate) type RoomCtrlChannel = mpsc::Sender<RoomCtrl>;
+
+struct RoomHandle {
+    join: JoinHandle<()>,
+    ctrl: RoomCtrlChannel,
+}
+
+/// Keeps track of every room runtime spawned by this server.
+#[derive(Clone, Default)]
+pub struct RoomRegistry {
+    rooms: Arc<Mutex<HashMap<InternalRoomId, RoomHandle>>>,
+}
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns the room's task and registers it under `id`.
+    /// The task is expected to call `unregister` once it finishes.
+    pub(super) async fn spawn<F>(&self, id: InternalRoomId, ctrl: RoomCtrlChannel, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        // Holding the lock while spawning so the task cannot
+        // unregister itself before it gets registered.
+        let mut rooms = self.rooms.lock().await;
+        let join = tokio::spawn(task);
+        if rooms
+            .insert(id.clone(), RoomHandle { join, ctrl })
+            .is_some()
+        {
+            warn!("{} was already registered", id);
+        }
+    }
+
+    pub(super) async fn unregister(&self, id: &InternalRoomId) {
+        self.rooms.lock().await.remove(id);
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.rooms.lock().await.is_empty()
+    }
+
+    pub async fn list(&self) -> Vec<dto::RoomStatus> {
+        let rooms: Vec<_> = self
+            .rooms
+            .lock()
+            .await
+            .values()
+            .map(|room| room.ctrl.clone())
+            .collect();
+        let mut statuses = Vec::with_capacity(rooms.len());
+        for ctrl in rooms {
+            if let Some(status) = query_status(ctrl).await {
+                statuses.push(status);
+            }
+        }
+        statuses
+    }
+
+    pub async fn status(&self, id: &str) -> Option<dto::RoomStatus> {
+        let id = internal_id(id)?;
+        let ctrl = self.rooms.lock().await.get(&id)?.ctrl.clone();
+        query_status(ctrl).await
+    }
+
+    /// Stops the room and waits for its task to finish.
+    /// Returns false if there is no such room.
+    pub async fn shutdown(&self, id: &str) -> bool {
+        let id = match internal_id(id) {
+            Some(id) => id,
+            None => return false,
+        };
+        let room = match self.rooms.lock().await.remove(&id) {
+            Some(room) => room,
+            None => return false,
+        };
+        info!("Shutting down {}", id);
+        stop_room(&id, room).await;
+        true
+    }
+
+    /// Tells every registered room that the server is going down and
+    /// waits for them to finish for at most `grace` in total.
+    pub async fn shutdown_all(&self, grace: Duration) {
+        let mut rooms: Vec<_> = self.rooms.lock().await.drain().collect();
+        info!("Shutting down {} rooms", rooms.len());
+        // notify everyone first so rooms can drain at the same time
+        for (id, room) in rooms.iter_mut() {
+            if room.ctrl.send(RoomCtrl::ServerShutdown).await.is_err() {
+                warn!("{} has already stopped", id);
+            }
+        }
+        let deadline = Instant::now() + grace;
+        for (id, room) in rooms {
+            match time::timeout_at(deadline, room.join).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => error!("couldn't join on {}: {}", id, err),
+                Err(_) => warn!("{} did not finish within the grace period", id),
+            }
+        }
+    }
+}
+
+fn internal_id(id: &str) -> Option<InternalRoomId> {
+    decode_room_id(id).map(|entry_id| InternalRoomId::new(entry_id, id.to_owned()))
+}
+
+async fn query_status(mut ctrl: RoomCtrlChannel) -> Option<dto::RoomStatus> {
+    let (tx, rx) = oneshot::channel();
+    // the room might have finished in the meantime
+    ctrl.send(RoomCtrl::Status(tx)).await.ok()?;
+    rx.await.ok()
+}
+
+async fn stop_room(id: &InternalRoomId, mut room: RoomHandle) {
+    if room.ctrl.send(RoomCtrl::Shutdown).await.is_err() {
+        warn!("{} has already stopped", id);
+    }
+    if let Err(err) = room.join.await {
+        error!("couldn't join on {}: {}", id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn fake_room(
+        registry: RoomRegistry,
+        id: InternalRoomId,
+    ) -> (RoomCtrlChannel, impl Future<Output = ()>) {
+        let (tx, mut rx) = mpsc::channel(1);
+        let task = async move {
+            while let Some(ctrl) = rx.recv().await {
+                match ctrl {
+                    RoomCtrl::Status(tx) => {
+                        let _ = tx.send(dto::RoomStatus {
+                            id: id.as_base64.clone(),
+                            mode: Default::default(),
+                            state: crate::room::model::RoomState::AcceptingPlayers,
+                            players: 0,
+                            players_limit: 2,
+                            rounds_limit: 1,
+                            round: None,
+                        });
+                    }
+                    RoomCtrl::Shutdown | RoomCtrl::ServerShutdown => break,
+                }
+            }
+            registry.unregister(&id).await;
+        };
+        (tx, task)
+    }
+
+    fn room_id(byte: u8) -> InternalRoomId {
+        let id = [byte; 12];
+        InternalRoomId::new(id, crate::service::encode_room_id(&id))
+    }
+
+    #[tokio::test]
+    async fn rooms_can_be_listed_and_stopped() {
+        let registry = RoomRegistry::new();
+        for byte in 0..3 {
+            let id = room_id(byte);
+            let (ctrl, task) = fake_room(registry.clone(), id.clone());
+            registry.spawn(id, ctrl, task).await;
+        }
+        assert_eq!(registry.list().await.len(), 3);
+        let id = room_id(1).as_base64;
+        assert_eq!(registry.status(&id).await.unwrap().id, id);
+        assert!(registry.shutdown(&id).await);
+        assert!(!registry.shutdown(&id).await);
+        assert!(registry.status(&id).await.is_none());
+        registry.shutdown_all(Duration::from_secs(1)).await;
+        assert!(registry.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn shutdown_does_not_wait_for_stuck_rooms() {
+        let registry = RoomRegistry::new();
+        let (ctrl, _rx) = mpsc::channel(1);
+        let stuck = futures::future::pending();
+        registry.spawn(room_id(3), ctrl, stuck).await;
+        registry.shutdown_all(Duration::from_millis(10)).await;
+        assert!(registry.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn finished_rooms_unregister_themselves() {
+        let registry = RoomRegistry::new();
+        let id = room_id(7);
+        let (mut ctrl, task) = fake_room(registry.clone(), id.clone());
+        registry.spawn(id, ctrl.clone(), task).await;
+        ctrl.send(RoomCtrl::Shutdown).await.unwrap();
+        // give the task a chance to finish
+        for _ in 0..100 {
+            if registry.is_empty().await {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert!(registry.is_empty().await);
+    }
+}
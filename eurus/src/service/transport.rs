@@ -0,0 +1,268 @@
+//! Transports carrying messages between the room runtime and its clients.
+//!
+//! The runtime only needs to subscribe to topics, publish on them and
+//! read whatever arrives so anything providing that can be used.
+//! `PahoTransport` talks to a real mqtt broker while `MemoryTransport`
+//! keeps everything in-process, which is handy for testing rooms.
+use std::{
+    collections::HashSet,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use futures::{future, Stream, StreamExt};
+use paho_mqtt as mqtt;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use super::Topic;
+
+type Result<T> = std::result::Result<T, TransportError>;
+
+/// Incoming packets, `None` means the connection got lost.
+pub type PacketStream = Pin<Box<dyn Stream<Item = Option<Packet>> + Send>>;
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("{0}")]
+    MqttError(#[from] mqtt::Error),
+    #[error("transport is closed")]
+    Closed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub topic: Topic,
+    pub payload: String,
+}
+
+#[async_trait]
+pub trait Transport: Send + 'static {
+    /// Takes the stream of incoming packets.
+    /// Returns `None` if it has already been taken.
+    fn incoming(&mut self) -> Option<PacketStream>;
+    async fn subscribe(&mut self, topics: &[Topic]) -> Result<()>;
+    async fn unsubscribe(&mut self, topics: &[Topic]) -> Result<()>;
+    async fn publish(&mut self, packet: Packet) -> Result<()>;
+    async fn reconnect(&mut self) -> Result<()>;
+    async fn disconnect(&mut self) -> Result<()>;
+    fn is_connected(&self) -> bool;
+}
+
+pub struct PahoTransport {
+    cli: mqtt::AsyncClient,
+    incoming: Option<PacketStream>,
+}
+
+impl PahoTransport {
+    pub fn new(opts: mqtt::CreateOptions) -> Result<Self> {
+        let mut cli = mqtt::AsyncClient::new(opts)?;
+        // the stream has to be created before connecting
+        // otherwise we could miss some messages
+        let stream = cli.get_stream(25).map(|msg| msg.map(Packet::from)); // arbitrarily chosen
+        Ok(Self {
+            cli,
+            incoming: Some(Box::pin(stream)),
+        })
+    }
+
+    pub async fn connect(&mut self, opts: mqtt::ConnectOptions) -> Result<()> {
+        self.cli.connect(opts).await?;
+        Ok(())
+    }
+}
+
+impl From<mqtt::Message> for Packet {
+    fn from(msg: mqtt::Message) -> Self {
+        Self {
+            topic: msg.topic().into(),
+            payload: msg.payload_str().into_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for PahoTransport {
+    fn incoming(&mut self) -> Option<PacketStream> {
+        self.incoming.take()
+    }
+
+    async fn subscribe(&mut self, topics: &[Topic]) -> Result<()> {
+        let qos: Vec<i32> = vec![0; topics.len()];
+        let qosv = self.cli.subscribe_many(topics, &qos).await?;
+        debug!("QoS granted: {:?}", qosv);
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, topics: &[Topic]) -> Result<()> {
+        self.cli.unsubscribe_many(topics).await?;
+        Ok(())
+    }
+
+    async fn publish(&mut self, packet: Packet) -> Result<()> {
+        let msg = mqtt::MessageBuilder::new()
+            .topic(packet.topic)
+            .payload(packet.payload)
+            .qos(0)
+            .finalize();
+        self.cli.publish(msg).await?;
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        self.cli.reconnect().await?;
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.cli.disconnect(None).await?;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.cli.is_connected()
+    }
+}
+
+/// Transport living entirely in memory. Everything it publishes ends up
+/// in the paired `MemoryClient` which can also send packets back.
+/// Packets sent on topics the transport is not subscribed to are dropped
+/// just like a broker would do, subscriptions can use mqtt wildcards.
+pub struct MemoryTransport {
+    connected: Arc<AtomicBool>,
+    subscriptions: Arc<Mutex<HashSet<Topic>>>,
+    incoming: Option<PacketStream>,
+    outgoing: mpsc::UnboundedSender<Packet>,
+}
+
+/// The other end of the `MemoryTransport`.
+pub struct MemoryClient {
+    incoming: mpsc::UnboundedSender<Option<Packet>>,
+    outgoing: mpsc::UnboundedReceiver<Packet>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryClient) {
+        let (in_tx, in_rx) = mpsc::unbounded_channel();
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let connected = Arc::new(AtomicBool::new(true));
+        let subscriptions = Arc::new(Mutex::new(HashSet::new()));
+        let incoming = {
+            let connected = connected.clone();
+            let subscriptions = subscriptions.clone();
+            in_rx.filter_map(move |packet: Option<Packet>| {
+                let packet = match packet {
+                    Some(packet) => {
+                        let subs = subscriptions.lock().unwrap();
+                        if subs
+                            .iter()
+                            .any(|sub: &Topic| topic_matches(sub, &packet.topic))
+                        {
+                            Some(Some(packet))
+                        } else {
+                            None
+                        }
+                    }
+                    None => {
+                        connected.store(false, Ordering::SeqCst);
+                        Some(None)
+                    }
+                };
+                future::ready(packet)
+            })
+        };
+        let transport = MemoryTransport {
+            connected,
+            subscriptions,
+            incoming: Some(Box::pin(incoming)),
+            outgoing: out_tx,
+        };
+        let client = MemoryClient {
+            incoming: in_tx,
+            outgoing: out_rx,
+        };
+        (transport, client)
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    fn incoming(&mut self) -> Option<PacketStream> {
+        self.incoming.take()
+    }
+
+    async fn subscribe(&mut self, topics: &[Topic]) -> Result<()> {
+        let mut subs = self.subscriptions.lock().unwrap();
+        subs.extend(topics.iter().cloned());
+        Ok(())
+    }
+
+    async fn unsubscribe(&mut self, topics: &[Topic]) -> Result<()> {
+        let mut subs = self.subscriptions.lock().unwrap();
+        for topic in topics {
+            subs.remove(topic);
+        }
+        Ok(())
+    }
+
+    async fn publish(&mut self, packet: Packet) -> Result<()> {
+        if !self.is_connected() {
+            return Err(TransportError::Closed);
+        }
+        self.outgoing
+            .send(packet)
+            .map_err(|_| TransportError::Closed)
+    }
+
+    async fn reconnect(&mut self) -> Result<()> {
+        self.connected.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        self.connected.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+}
+
+/// Checks the topic against a subscription which might contain
+/// `+` matching a single level or a trailing `#` matching the rest.
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => (),
+            (part, Some(level)) if part == level => (),
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+impl MemoryClient {
+    /// Sends `payload` to the transport as if it was published on `topic`.
+    pub fn publish(&self, topic: Topic, payload: String) {
+        let _ = self.incoming.send(Some(Packet { topic, payload }));
+    }
+
+    /// Simulates losing the connection to the broker.
+    pub fn drop_connection(&self) {
+        let _ = self.incoming.send(None);
+    }
+
+    /// Next packet published by the transport,
+    /// `None` once the transport is gone.
+    pub async fn recv(&mut self) -> Option<Packet> {
+        self.outgoing.recv().await
+    }
+}